@@ -0,0 +1,783 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use crate::loader::Image;
+use crate::opcode::Opcode;
+use crate::symbols::SymbolTable;
+use crate::trap::TrapCode;
+
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    pub image: Image,
+    pub symbols: SymbolTable,
+}
+
+/* 1-based line and column of the source text an error points at, length in characters */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AssemblerError {
+    pub span: Span,
+    pub kind: AssemblerErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssemblerErrorKind {
+    UnknownMnemonic(String),
+    UndefinedLabel(String),
+    DuplicateLabel { name: String, first_line: usize },
+    InvalidLabel(String),
+    InvalidOperand(String),
+    ExpectedRegister(String),
+    OperandCount { expected: usize, found: usize },
+    OffsetOutOfRange { offset: i32, bits: u32 },
+    ImmediateOutOfRange { value: i32, bits: u32 },
+    UnterminatedString,
+    MissingOrig,
+    MissingEnd,
+    DuplicateOrig,
+    ProgramTooLarge,
+}
+
+impl fmt::Display for AssemblerErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssemblerErrorKind::UnknownMnemonic(name) => write!(f, "unknown mnemonic `{}`", name),
+            AssemblerErrorKind::UndefinedLabel(name) => write!(f, "undefined label `{}`", name),
+            AssemblerErrorKind::DuplicateLabel { name, first_line } => {
+                write!(f, "duplicate label `{}` (first defined on line {})", name, first_line)
+            },
+            AssemblerErrorKind::InvalidLabel(name) => write!(f, "invalid label `{}`", name),
+            AssemblerErrorKind::InvalidOperand(operand) => write!(f, "invalid operand `{}`", operand),
+            AssemblerErrorKind::ExpectedRegister(operand) => {
+                write!(f, "expected a register R0-R7, found `{}`", operand)
+            },
+            AssemblerErrorKind::OperandCount { expected, found } => {
+                write!(f, "expected {} operand(s), found {}", expected, found)
+            },
+            AssemblerErrorKind::OffsetOutOfRange { offset, bits } => {
+                let limit = 1 << (bits - 1);
+                write!(f, "offset {} does not fit in {} bits ({}..={})", offset, bits, -limit, limit - 1)
+            },
+            AssemblerErrorKind::ImmediateOutOfRange { value, bits } => {
+                write!(f, "immediate {} does not fit in {} bits", value, bits)
+            },
+            AssemblerErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            AssemblerErrorKind::MissingOrig => write!(f, "expected .ORIG before the first statement"),
+            AssemblerErrorKind::MissingEnd => write!(f, "missing .END"),
+            AssemblerErrorKind::DuplicateOrig => write!(f, ".ORIG inside an open .ORIG block"),
+            AssemblerErrorKind::ProgramTooLarge => write!(f, "program runs past the end of memory"),
+        }
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.span.line, self.span.column, self.kind)
+    }
+}
+
+impl error::Error for AssemblerError {}
+
+impl AssemblerError {
+    fn new(span: Span, kind: AssemblerErrorKind) -> Self {
+        AssemblerError { span, kind }
+    }
+
+    /* rustc-style report with the offending source line and a caret underline */
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let text = source.lines().nth(self.span.line - 1).unwrap_or("");
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        /* keep tabs from the source so the carets line up however the terminal expands them */
+        let padding: String = text
+            .chars()
+            .take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.kind,
+            gutter,
+            path,
+            self.span.line,
+            self.span.column,
+            gutter,
+            self.span.line,
+            text,
+            gutter,
+            padding,
+            "^".repeat(self.span.length.max(1)),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+impl Token {
+    fn error(&self, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError::new(self.span, kind)
+    }
+}
+
+enum Statement {
+    Instruction(Token, Vec<Token>),
+    Fill(Token),
+    Blkw(u16),
+    Stringz(Vec<u16>),
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction(_, _) | Statement::Fill(_) => 1,
+            Statement::Blkw(count) => *count as usize,
+            Statement::Stringz(chars) => chars.len() + 1,
+        }
+    }
+}
+
+struct Line {
+    address: u16,
+    statement: Statement,
+}
+
+/* assembles the whole file, reporting every error found rather than stopping at the first */
+pub fn assemble(source: &str) -> Result<Program, Vec<AssemblerError>> {
+    let mut errors: Vec<AssemblerError> = Vec::new();
+    let mut origin: Option<u16> = None;
+    let mut ended = false;
+    let mut address: usize = 0;
+    let mut symbols = SymbolTable::new();
+    let mut definitions: BTreeMap<String, usize> = BTreeMap::new();
+    let mut lines: Vec<Line> = Vec::new();
+    let mut last_span = Span { line: 1, column: 1, length: 0 };
+
+    /* first pass: assign an address to every statement and collect labels */
+    for (index, text) in source.lines().enumerate() {
+        let mut tokens = match tokenize(text, index + 1) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                errors.push(error);
+                continue;
+            },
+        };
+        let Some(last) = tokens.last() else {
+            continue;
+        };
+        last_span = last.span;
+        let mut label = None;
+        if !is_keyword(&tokens[0].text) {
+            label = Some(tokens.remove(0));
+        }
+        if origin.is_none() {
+            match tokens.first() {
+                Some(token) if token.text.eq_ignore_ascii_case(".ORIG") && label.is_none() => {
+                    match parse_orig(token, &tokens[1..]) {
+                        Ok(value) => {
+                            origin = Some(value);
+                            address = value as usize;
+                        },
+                        Err(error) => errors.push(error),
+                    }
+                },
+                _ => {
+                    let token = label.as_ref().or(tokens.first()).unwrap();
+                    errors.push(token.error(AssemblerErrorKind::MissingOrig));
+                    break;
+                },
+            }
+            continue;
+        }
+        if let (Some(name), Some(next)) = (&label, tokens.first()) {
+            if parse_register(&next.text).is_some() || parse_number(&next.text).is_some() {
+                /* `FOO R1`: FOO is a mistyped mnemonic rather than a label */
+                errors.push(name.error(AssemblerErrorKind::UnknownMnemonic(name.text.clone())));
+                address += 1;
+                continue;
+            }
+        }
+        if let Some(label) = label {
+            let name = label.text.strip_suffix(':').unwrap_or(&label.text).to_string();
+            if !is_valid_label(&name) {
+                errors.push(label.error(AssemblerErrorKind::InvalidLabel(name)));
+            } else if let Some(&first_line) = definitions.get(&name) {
+                errors.push(label.error(AssemblerErrorKind::DuplicateLabel { name, first_line }));
+            } else if address <= u16::MAX as usize {
+                definitions.insert(name.clone(), label.span.line);
+                symbols.insert(name, address as u16);
+            }
+        }
+        let Some(keyword) = tokens.first() else {
+            continue;
+        };
+        if keyword.text.eq_ignore_ascii_case(".END") {
+            ended = true;
+            break;
+        }
+        /* a statement that fails to parse still takes a word so later labels keep their addresses */
+        let (size, statement) = match parse_statement(keyword, &tokens[1..]) {
+            Ok(statement) => (statement.size(), Some(statement)),
+            Err(error) => {
+                errors.push(error);
+                (1, None)
+            },
+        };
+        if address + size > u16::MAX as usize + 1 {
+            errors.push(keyword.error(AssemblerErrorKind::ProgramTooLarge));
+            break;
+        }
+        if let Some(statement) = statement {
+            lines.push(Line { address: address as u16, statement });
+        }
+        address += size;
+    }
+    let Some(origin) = origin else {
+        if errors.is_empty() {
+            errors.push(AssemblerError::new(last_span, AssemblerErrorKind::MissingOrig));
+        }
+        return Err(errors);
+    };
+    if !ended && errors.iter().all(|error| error.kind != AssemblerErrorKind::ProgramTooLarge) {
+        let span = Span { column: last_span.column + last_span.length, length: 1, ..last_span };
+        errors.push(AssemblerError::new(span, AssemblerErrorKind::MissingEnd));
+    }
+
+    /* second pass: encode every statement now that all labels are known */
+    let mut words: Vec<u16> = vec![0; address - origin as usize];
+    for line in &lines {
+        let index = (line.address - origin) as usize;
+        let result = match &line.statement {
+            Statement::Instruction(mnemonic, operands) => {
+                encode(mnemonic, operands, line.address, &symbols).map(|word| words[index] = word)
+            },
+            Statement::Fill(operand) => fill(operand, &symbols).map(|word| words[index] = word),
+            Statement::Blkw(_) => Ok(()),
+            Statement::Stringz(chars) => {
+                words[index..index + chars.len()].copy_from_slice(chars);
+                Ok(())
+            },
+        };
+        if let Err(error) = result {
+            errors.push(error);
+        }
+    }
+    if !errors.is_empty() {
+        errors.sort_by_key(|error| (error.span.line, error.span.column));
+        return Err(errors);
+    }
+    Ok(Program {
+        image: Image::new(origin, words),
+        symbols,
+    })
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AssemblerError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().enumerate().peekable();
+    let mut current = String::new();
+    let mut start = 0;
+    while let Some((column, c)) = chars.next() {
+        match c {
+            ';' => break,
+            '"' => {
+                if current.is_empty() {
+                    start = column;
+                }
+                current.push(c);
+                let mut escaped = false;
+                let mut terminated = false;
+                for (_, c) in chars.by_ref() {
+                    current.push(c);
+                    if escaped {
+                        escaped = false;
+                    } else if c == '\\' {
+                        escaped = true;
+                    } else if c == '"' {
+                        terminated = true;
+                        break;
+                    }
+                }
+                if !terminated {
+                    let span = Span { line, column: start + 1, length: current.chars().count() };
+                    return Err(AssemblerError::new(span, AssemblerErrorKind::UnterminatedString));
+                }
+            },
+            c if c.is_whitespace() || c == ',' => {
+                if !current.is_empty() {
+                    let length = current.chars().count();
+                    let span = Span { line, column: start + 1, length };
+                    tokens.push(Token { text: std::mem::take(&mut current), span });
+                }
+            },
+            _ => {
+                if current.is_empty() {
+                    start = column;
+                }
+                current.push(c)
+            },
+        }
+    }
+    if !current.is_empty() {
+        let length = current.chars().count();
+        tokens.push(Token { text: current, span: Span { line, column: start + 1, length } });
+    }
+    Ok(tokens)
+}
+
+fn is_keyword(token: &str) -> bool {
+    let upper = token.to_ascii_uppercase();
+    matches!(
+        upper.as_str(),
+        ".ORIG" | ".END" | ".FILL" | ".BLKW" | ".STRINGZ"
+    ) || branch_flags(&upper).is_some() || encoder_for(&upper).is_some()
+}
+
+fn is_valid_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    /* a reference to `x1` or `b101` would be read as a number, never as the label */
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && parse_register(name).is_none()
+        && parse_number(name).is_none()
+}
+
+fn expect_operands(keyword: &Token, operands: &[Token], expected: usize) -> Result<(), AssemblerError> {
+    if operands.len() != expected {
+        /* point at the first surplus operand, or at the keyword when operands are missing */
+        let token = operands.get(expected).unwrap_or(keyword);
+        return Err(token.error(AssemblerErrorKind::OperandCount { expected, found: operands.len() }));
+    }
+    Ok(())
+}
+
+fn parse_orig(keyword: &Token, operands: &[Token]) -> Result<u16, AssemblerError> {
+    expect_operands(keyword, operands, 1)?;
+    let value = number(&operands[0])?;
+    check_unsigned(value, 16).map_err(|kind| operands[0].error(kind))
+}
+
+fn parse_statement(keyword: &Token, operands: &[Token]) -> Result<Statement, AssemblerError> {
+    let upper = keyword.text.to_ascii_uppercase();
+    match upper.as_str() {
+        ".ORIG" => Err(keyword.error(AssemblerErrorKind::DuplicateOrig)),
+        ".FILL" => {
+            expect_operands(keyword, operands, 1)?;
+            Ok(Statement::Fill(operands[0].clone()))
+        },
+        ".BLKW" => {
+            expect_operands(keyword, operands, 1)?;
+            let count = number(&operands[0])?;
+            Ok(Statement::Blkw(check_unsigned(count, 16).map_err(|kind| operands[0].error(kind))?))
+        },
+        ".STRINGZ" => {
+            expect_operands(keyword, operands, 1)?;
+            Ok(Statement::Stringz(parse_string(&operands[0])?))
+        },
+        _ if is_keyword(&upper) => {
+            let expected = operand_count(&upper);
+            expect_operands(keyword, operands, expected)?;
+            Ok(Statement::Instruction(keyword.clone(), operands.to_vec()))
+        },
+        _ => {
+            Err(keyword.error(AssemblerErrorKind::UnknownMnemonic(keyword.text.clone())))
+        },
+    }
+}
+
+/* accepts #decimal, plain decimal, xHEX/0xHEX and bBINARY/0bBINARY, each optionally negative */
+pub fn parse_number(text: &str) -> Option<i32> {
+    let (text, radix) = if let Some(rest) = text.strip_prefix('#') {
+        (rest, 10)
+    } else if let Some(rest) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (rest, 16)
+    } else if let Some(rest) = text.strip_prefix('x').or_else(|| text.strip_prefix('X')) {
+        (rest, 16)
+    } else if let Some(rest) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        (rest, 2)
+    } else if let Some(rest) = text.strip_prefix('b').or_else(|| text.strip_prefix('B')) {
+        (rest, 2)
+    } else {
+        (text, 10)
+    };
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    if value > 0xFFFF {
+        return None;
+    }
+    Some(if negative { -value } else { value })
+}
+
+fn number(token: &Token) -> Result<i32, AssemblerError> {
+    parse_number(&token.text).ok_or_else(|| token.error(AssemblerErrorKind::InvalidOperand(token.text.clone())))
+}
+
+fn parse_string(token: &Token) -> Result<Vec<u16>, AssemblerError> {
+    let invalid = || token.error(AssemblerErrorKind::InvalidOperand(token.text.clone()));
+    let inner = token
+        .text
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut chars = Vec::new();
+    let mut escaped = inner.chars();
+    while let Some(c) = escaped.next() {
+        let c = if c == '\\' {
+            match escaped.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('e') => '\x1B',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err(invalid()),
+            }
+        } else {
+            c
+        };
+        if !c.is_ascii() {
+            return Err(invalid());
+        }
+        chars.push(c as u16);
+    }
+    Ok(chars)
+}
+
+fn parse_register(text: &str) -> Option<u16> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('R' | 'r'), Some(digit @ '0'..='7'), None) => Some(digit as u16 - '0' as u16),
+        _ => None,
+    }
+}
+
+fn register(token: &Token) -> Result<u16, AssemblerError> {
+    parse_register(&token.text)
+        .ok_or_else(|| token.error(AssemblerErrorKind::ExpectedRegister(token.text.clone())))
+}
+
+fn resolve_label(token: &Token, symbols: &SymbolTable) -> Result<u16, AssemblerError> {
+    symbols
+        .get(&token.text)
+        .copied()
+        .ok_or_else(|| token.error(AssemblerErrorKind::UndefinedLabel(token.text.clone())))
+}
+
+fn check_signed(value: i32, bits: u32) -> bool {
+    let limit = 1 << (bits - 1);
+    (-limit..limit).contains(&value)
+}
+
+fn check_unsigned(value: i32, bits: u32) -> Result<u16, AssemblerErrorKind> {
+    if value < 0 || value >= 1 << bits {
+        return Err(AssemblerErrorKind::ImmediateOutOfRange { value, bits });
+    }
+    Ok(value as u16)
+}
+
+fn fill(token: &Token, symbols: &SymbolTable) -> Result<u16, AssemblerError> {
+    let value = match parse_number(&token.text) {
+        Some(value) => value,
+        None => resolve_label(token, symbols)? as i32,
+    };
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(token.error(AssemblerErrorKind::ImmediateOutOfRange { value, bits: 16 }));
+    }
+    Ok(value as u16)
+}
+
+fn immediate(token: &Token, bits: u32) -> Result<u16, AssemblerError> {
+    let value = number(token)?;
+    if !check_signed(value, bits) {
+        return Err(token.error(AssemblerErrorKind::ImmediateOutOfRange { value, bits }));
+    }
+    Ok(value as u16 & ((1 << bits) - 1))
+}
+
+/* the offset6 of LDR/STR is relative to a base register rather than the PC */
+fn offset(token: &Token, bits: u32) -> Result<u16, AssemblerError> {
+    let offset = number(token)?;
+    if !check_signed(offset, bits) {
+        return Err(token.error(AssemblerErrorKind::OffsetOutOfRange { offset, bits }));
+    }
+    Ok(offset as u16 & ((1 << bits) - 1))
+}
+
+/* a PC-relative operand is either a label or a literal offset */
+fn pc_offset(token: &Token, address: u16, bits: u32, symbols: &SymbolTable) -> Result<u16, AssemblerError> {
+    let offset = match parse_number(&token.text) {
+        Some(offset) => offset,
+        None => resolve_label(token, symbols)? as i32 - (address as i32 + 1),
+    };
+    if !check_signed(offset, bits) {
+        return Err(token.error(AssemblerErrorKind::OffsetOutOfRange { offset, bits }));
+    }
+    Ok(offset as u16 & ((1 << bits) - 1))
+}
+
+fn branch_flags(mnemonic: &str) -> Option<u16> {
+    let flags = mnemonic.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0b111);
+    }
+    let mut value = 0;
+    let mut last = 3;
+    for c in flags.chars() {
+        let bit = match c {
+            'N' => 2,
+            'Z' => 1,
+            'P' => 0,
+            _ => return None,
+        };
+        if bit >= last {
+            return None;
+        }
+        value |= 1 << bit;
+        last = bit;
+    }
+    Some(value)
+}
+
+fn operand_count(mnemonic: &str) -> usize {
+    match mnemonic {
+        "ADD" | "AND" | "LDR" | "STR" => 3,
+        "NOT" | "LD" | "LDI" | "LEA" | "ST" | "STI" => 2,
+        "RET" | "RTI" | "RES" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => 0,
+        _ => 1,
+    }
+}
+
+/* encoders run after the operand count has been checked against `operand_count` */
+type Encoder = fn(&[Token], u16, &SymbolTable) -> Result<u16, AssemblerError>;
+
+fn encoder_for(mnemonic: &str) -> Option<Encoder> {
+    let encoder: Encoder = match mnemonic {
+        "ADD" => |operands, _, _| encode_arithmetic(Opcode::ADD, operands),
+        "AND" => |operands, _, _| encode_arithmetic(Opcode::AND, operands),
+        "NOT" => |operands, _, _| {
+            Ok(op(Opcode::NOT) | register(&operands[0])? << 9 | register(&operands[1])? << 6 | 0x3F)
+        },
+        "LD" => |operands, address, symbols| encode_pc_relative(Opcode::LD, operands, address, symbols),
+        "LDI" => |operands, address, symbols| encode_pc_relative(Opcode::LDI, operands, address, symbols),
+        "LEA" => |operands, address, symbols| encode_pc_relative(Opcode::LEA, operands, address, symbols),
+        "ST" => |operands, address, symbols| encode_pc_relative(Opcode::ST, operands, address, symbols),
+        "STI" => |operands, address, symbols| encode_pc_relative(Opcode::STI, operands, address, symbols),
+        "LDR" => |operands, _, _| encode_base_offset(Opcode::LDR, operands),
+        "STR" => |operands, _, _| encode_base_offset(Opcode::STR, operands),
+        "JMP" => |operands, _, _| Ok(op(Opcode::JMP) | register(&operands[0])? << 6),
+        "RET" => |_, _, _| Ok(op(Opcode::JMP) | 7 << 6),
+        "JSR" => |operands, address, symbols| {
+            Ok(op(Opcode::JSR) | 1 << 11 | pc_offset(&operands[0], address, 11, symbols)?)
+        },
+        "JSRR" => |operands, _, _| Ok(op(Opcode::JSR) | register(&operands[0])? << 6),
+        "RTI" => |_, _, _| Ok(op(Opcode::RTI)),
+        "RES" => |_, _, _| Ok(op(Opcode::RES)),
+        "TRAP" => |operands, _, _| {
+            let value = number(&operands[0])?;
+            Ok(op(Opcode::TRAP) | check_unsigned(value, 8).map_err(|kind| operands[0].error(kind))?)
+        },
+        "GETC" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::GETC as u16),
+        "OUT" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::OUT as u16),
+        "PUTS" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::PUTS as u16),
+        "IN" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::IN as u16),
+        "PUTSP" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::PUTSP as u16),
+        "HALT" => |_, _, _| Ok(op(Opcode::TRAP) | TrapCode::HALT as u16),
+        _ => return None,
+    };
+    Some(encoder)
+}
+
+fn encode(mnemonic: &Token, operands: &[Token], address: u16, symbols: &SymbolTable) -> Result<u16, AssemblerError> {
+    let upper = mnemonic.text.to_ascii_uppercase();
+    if let Some(flags) = branch_flags(&upper) {
+        return Ok(op(Opcode::BR) | flags << 9 | pc_offset(&operands[0], address, 9, symbols)?);
+    }
+    match encoder_for(&upper) {
+        Some(encoder) => encoder(operands, address, symbols),
+        None => Err(mnemonic.error(AssemblerErrorKind::UnknownMnemonic(mnemonic.text.clone()))),
+    }
+}
+
+fn op(opcode: Opcode) -> u16 {
+    (opcode as u16) << 12
+}
+
+fn encode_arithmetic(opcode: Opcode, operands: &[Token]) -> Result<u16, AssemblerError> {
+    let word = op(opcode) | register(&operands[0])? << 9 | register(&operands[1])? << 6;
+    match parse_register(&operands[2].text) {
+        Some(sr2) => Ok(word | sr2),
+        None => Ok(word | 1 << 5 | immediate(&operands[2], 5)?),
+    }
+}
+
+fn encode_pc_relative(opcode: Opcode, operands: &[Token], address: u16, symbols: &SymbolTable) -> Result<u16, AssemblerError> {
+    Ok(op(opcode) | register(&operands[0])? << 9 | pc_offset(&operands[1], address, 9, symbols)?)
+}
+
+fn encode_base_offset(opcode: Opcode, operands: &[Token]) -> Result<u16, AssemblerError> {
+    Ok(op(opcode) | register(&operands[0])? << 9 | register(&operands[1])? << 6 | offset(&operands[2], 6)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(source: &str) -> Vec<u16> {
+        assemble(source).unwrap().image.words
+    }
+
+    #[test]
+    fn test_assemble_all_opcodes() {
+        let program = assemble(
+            ".ORIG x3000
+            LOOP BRnz LOOP
+                 ADD R1, R2, R3
+                 ADD R1, R2, #-1
+                 LD R0, DATA
+                 ST R0, DATA
+                 JSR LOOP
+                 JSRR R4
+                 AND R5, R5, #0
+                 LDR R1, R6, #-32
+                 STR R1, R6, #31
+                 RTI
+                 NOT R2, R3
+                 LDI R0, DATA
+                 STI R0, DATA
+                 JMP R2
+                 RET
+                 RES
+                 LEA R0, LOOP
+                 TRAP x25
+            DATA .FILL xBEEF
+            .END",
+        )
+        .unwrap();
+        assert_eq!(program.image.origin, 0x3000);
+        assert_eq!(
+            program.image.words,
+            vec![
+                0x0DFF, 0x1283, 0x12BF, 0x200F, 0x300E, 0x4FFA, 0x4100, 0x5B60,
+                0x63A0, 0x739F, 0x8000, 0x94FF, 0xA006, 0xB005, 0xC080, 0xC1C0,
+                0xD000, 0xE1EE, 0xF025, 0xBEEF,
+            ]
+        );
+        assert_eq!(program.symbols.get("LOOP"), Some(&0x3000));
+        assert_eq!(program.symbols.get("DATA"), Some(&0x3013));
+    }
+
+    #[test]
+    fn test_assemble_pseudo_ops_and_traps() {
+        assert_eq!(
+            words(
+                ".orig x3000
+                 GETC
+                 OUT
+                 PUTS
+                 IN
+                 PUTSP
+                 HALT
+                 .BLKW 2
+                 .STRINGZ \"a\\n\"
+                 .FILL #-1
+                 .FILL b101
+                 .FILL END
+                 END: .END"
+            ),
+            vec![0xF020, 0xF021, 0xF022, 0xF023, 0xF024, 0xF025, 0, 0, 0x61, 0x0A, 0, 0xFFFF, 0b101, 0x300E]
+        );
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("#10"), Some(10));
+        assert_eq!(parse_number("#-10"), Some(-10));
+        assert_eq!(parse_number("10"), Some(10));
+        assert_eq!(parse_number("x1F"), Some(0x1F));
+        assert_eq!(parse_number("0xFFFF"), Some(0xFFFF));
+        assert_eq!(parse_number("x-1"), Some(-1));
+        assert_eq!(parse_number("b1010"), Some(0b1010));
+        assert_eq!(parse_number("LOOP"), None);
+        assert_eq!(parse_number("x10000"), None);
+    }
+
+    fn error(line: usize, column: usize, length: usize, kind: AssemblerErrorKind) -> AssemblerError {
+        AssemblerError { span: Span { line, column, length }, kind }
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(
+            assemble(".ORIG x3000\nFOO R1\n.END").unwrap_err(),
+            vec![error(2, 1, 3, AssemblerErrorKind::UnknownMnemonic("FOO".to_string()))]
+        );
+        assert_eq!(
+            assemble("ADD R0, R0, R0").unwrap_err(),
+            vec![error(1, 1, 3, AssemblerErrorKind::MissingOrig)]
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nHALT").unwrap_err(),
+            vec![error(2, 5, 1, AssemblerErrorKind::MissingEnd)]
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nx1 ADD R0, R0, #1\nb101 .FILL x3000\n.END").unwrap_err(),
+            vec![
+                error(2, 1, 2, AssemblerErrorKind::InvalidLabel("x1".to_string())),
+                error(3, 1, 4, AssemblerErrorKind::InvalidLabel("b101".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_reports_every_error() {
+        let source = ".ORIG x3000
+A   ADD R0, R0, #16
+A   BR NOWHERE
+    LDR R1, R2, #32
+    JSR FAR
+    LD R3, FAR
+    MUL R1, R2
+    .BLKW x700
+FAR .FILL #0
+    .END";
+        assert_eq!(
+            assemble(source).unwrap_err(),
+            vec![
+                error(2, 17, 3, AssemblerErrorKind::ImmediateOutOfRange { value: 16, bits: 5 }),
+                error(3, 1, 1, AssemblerErrorKind::DuplicateLabel { name: "A".to_string(), first_line: 2 }),
+                error(3, 8, 7, AssemblerErrorKind::UndefinedLabel("NOWHERE".to_string())),
+                error(4, 17, 3, AssemblerErrorKind::OffsetOutOfRange { offset: 32, bits: 6 }),
+                error(5, 9, 3, AssemblerErrorKind::OffsetOutOfRange { offset: 0x702, bits: 11 }),
+                error(6, 12, 3, AssemblerErrorKind::OffsetOutOfRange { offset: 0x701, bits: 9 }),
+                error(7, 5, 3, AssemblerErrorKind::UnknownMnemonic("MUL".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_error() {
+        let source = ".ORIG x3000\n\tBR NOWHERE\n.END";
+        let errors = assemble(source).unwrap_err();
+        assert_eq!(
+            errors[0].render("prog.asm", source),
+            "error: undefined label `NOWHERE`\n --> prog.asm:2:5\n  |\n2 | \tBR NOWHERE\n  | \t   ^^^^^^^\n"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::cpu::{Limit, StepOutcome, VmError, VmErrorCause, CPU};
+use crate::register::{Register, GENERAL_REGISTERS};
+use crate::utils::json_string;
+
+#[derive(Debug)]
+pub enum HaltReason {
+    Halted,         /* HALT, or the MCR clock-enable bit was cleared */
+    InputExhausted, /* the program wanted a key after the scripted input ran out */
+    LimitReached(Limit),
+    Stopped,        /* a hook or a watchpoint asked to stop */
+    Fault(VmError),
+}
+
+impl HaltReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HaltReason::Halted => "halted",
+            HaltReason::InputExhausted => "input_exhausted",
+            HaltReason::LimitReached(Limit::Instructions) => "instruction_limit",
+            HaltReason::LimitReached(Limit::Time) => "time_limit",
+            HaltReason::LimitReached(Limit::Output) => "output_limit",
+            HaltReason::Stopped => "stopped",
+            HaltReason::Fault(_) => "fault",
+        }
+    }
+}
+
+/* how a headless run ended and the machine state it left behind */
+#[derive(Debug)]
+pub struct BatchSummary {
+    pub halt_reason: HaltReason,
+    pub instructions: u64,
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub psr: u16,
+}
+
+impl BatchSummary {
+    /* one JSON object; `output` is what the program wrote, decoded lossily as UTF-8 */
+    pub fn to_json(&self, output: &[u8]) -> String {
+        let mut json = String::new();
+        json.push_str(&format!("{{\"halt_reason\":\"{}\",\"error\":", self.halt_reason.name()));
+        match &self.halt_reason {
+            HaltReason::Fault(error) => json.push_str(&json_string(&error.to_string())),
+            _ => json.push_str("null"),
+        }
+        json.push_str(&format!(",\"instructions\":{},\"registers\":{{", self.instructions));
+        for (index, value) in self.registers.iter().enumerate() {
+            json.push_str(&format!("\"R{}\":{},", index, value));
+        }
+        json.push_str(&format!("\"PC\":{},\"PSR\":{}}},\"output\":", self.pc, self.psr));
+        json.push_str(&json_string(&String::from_utf8_lossy(output)));
+        json.push('}');
+        json
+    }
+}
+
+/* runs a machine that is ready to step until it halts, faults or hits one of its limits */
+pub fn run(cpu: &mut CPU) -> BatchSummary {
+    let start_count = cpu.instructions();
+    let result = cpu.run_until(|_| false);
+    let halt_reason = match result {
+        Ok(StepOutcome::LimitReached(limit)) => HaltReason::LimitReached(limit),
+        Ok(StepOutcome::Stopped | StepOutcome::Watched(_)) => HaltReason::Stopped,
+        Ok(_) => HaltReason::Halted,
+        Err(VmError { cause: VmErrorCause::InputClosed, .. }) => HaltReason::InputExhausted,
+        Err(error) => HaltReason::Fault(error),
+    };
+    let registers = cpu.registers();
+    BatchSummary {
+        halt_reason,
+        instructions: cpu.instructions() - start_count,
+        registers: GENERAL_REGISTERS.map(|register| registers.read(register)),
+        pc: registers.read(Register::PC),
+        psr: registers.psr(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::cpu::Limits;
+    use crate::machine::tests::test_machine;
+    use crate::os::assemble_os;
+
+    fn batch(source: &str, input: &[u8]) -> (BatchSummary, Vec<u8>) {
+        batch_limited(source, input, Limits::default())
+    }
+
+    fn batch_limited(source: &str, input: &[u8], limits: Limits) -> (BatchSummary, Vec<u8>) {
+        let (mut cpu, console, _) = test_machine(source, input, |machine| machine.limits(limits));
+        let summary = run(&mut cpu);
+        let output = console.borrow().output().to_vec();
+        (summary, output)
+    }
+
+    #[test]
+    fn test_batch_run() {
+        let source = ".ORIG x3000\nGETC\nOUT\nADD R1, R0, #0\nHALT\n.END";
+        let (summary, output) = batch(source, b"\"");
+        assert!(matches!(summary.halt_reason, HaltReason::Halted));
+        assert_eq!(summary.instructions, 4);
+        assert_eq!(summary.registers[1], b'"' as u16);
+        assert_eq!(output, b"\"HALT");
+        let json = summary.to_json(&output);
+        assert!(json.starts_with("{\"halt_reason\":\"halted\",\"error\":null,\"instructions\":4,"));
+        assert!(json.contains("\"R1\":34,"));
+        assert!(json.contains("\"PC\":12292,"));
+        assert!(json.ends_with(",\"output\":\"\\\"HALT\"}"));
+
+        let (summary, _) = batch(source, b"");
+        assert!(matches!(summary.halt_reason, HaltReason::InputExhausted));
+        assert_eq!(summary.instructions, 0);
+
+        /* checking KBSR with no input left isn't an error, only asking for the key is */
+        let peek = ".ORIG x3000\nLDI R1, KBSR\nHALT\nKBSR .FILL xFE00\n.END";
+        let (summary, _) = batch(peek, b"");
+        assert!(matches!(summary.halt_reason, HaltReason::Halted));
+        assert_eq!(summary.registers[1], 0);
+
+        let (summary, output) = batch(".ORIG x3000\n.FILL xD000\n.END", b"");
+        assert!(matches!(summary.halt_reason, HaltReason::Fault(_)));
+        assert!(summary.to_json(&output).contains("\"error\":\"fault at x3000"));
+    }
+
+    #[test]
+    fn test_batch_run_under_the_os_stops_in_the_halt_routine() {
+        let source = ".ORIG x3000\nAND R1, R1, #0\nADD R7, R1, #7\nADD R0, R1, #-1\nHALT\n.END";
+        let (mut cpu, console, _) = test_machine(source, b"", |machine| machine.os(true));
+        let summary = run(&mut cpu);
+        assert!(matches!(summary.halt_reason, HaltReason::Halted));
+        assert_eq!(console.borrow().output(), b"HALT");
+        /* the OS put the program's registers back before stopping the clock; R6 is the OS's */
+        assert_eq!(summary.registers[..6], [0xFFFF, 0, 0, 0, 0, 0]);
+        assert_eq!(summary.registers[7], 7);
+        assert_eq!(summary.pc, assemble_os().symbols["HALT_RESUME"]);
+        assert_eq!(summary.psr >> 15, 0);
+    }
+
+    #[test]
+    fn test_batch_limits() {
+        let spin = ".ORIG x3000\nLOOP BR LOOP\n.END";
+        let limits = Limits { max_instructions: Some(1000), ..Limits::default() };
+        let (summary, _) = batch_limited(spin, b"", limits);
+        assert_eq!(summary.halt_reason.name(), "instruction_limit");
+        assert_eq!(summary.instructions, 1000);
+
+        let limits = Limits { timeout: Some(Duration::from_millis(20)), ..Limits::default() };
+        let (summary, _) = batch_limited(spin, b"", limits);
+        assert_eq!(summary.halt_reason.name(), "time_limit");
+
+        let shout = ".ORIG x3000\nLD R0, BANG\nLOOP OUT\nBR LOOP\nBANG .FILL x21\n.END";
+        let limits = Limits { max_output: Some(5), ..Limits::default() };
+        let (summary, output) = batch_limited(shout, b"", limits);
+        assert_eq!(summary.halt_reason.name(), "output_limit");
+        assert_eq!(output, b"!!!!!");
+
+        /* the VM's HALT banner isn't the program's output */
+        let hello = ".ORIG x3000\nLEA R0, HELLO\nPUTS\nHALT\nHELLO .STRINGZ \"hello\"\n.END";
+        let (summary, output) = batch_limited(hello, b"", limits);
+        assert_eq!(summary.halt_reason.name(), "halted");
+        assert_eq!(output, b"helloHALT");
+    }
+}
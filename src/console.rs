@@ -0,0 +1,335 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::rc::Rc;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd::read;
+
+/*
+ * Where the machine's keyboard input comes from and its display output goes. read_byte blocks and
+ * reports closed input as UnexpectedEof; poll never blocks and is true only when a byte is waiting,
+ * so closed input just looks like a keyboard nobody is typing on.
+ */
+pub trait Console {
+    fn read_byte(&mut self) -> io::Result<u8>;
+    fn poll(&mut self) -> io::Result<bool>;
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/* the keyboard, display and native traps all talk to the same console */
+pub type SharedConsole = Rc<RefCell<dyn Console>>;
+
+/*
+ * Bytes read from a file descriptor but not yet taken by the machine. poll only asks the descriptor
+ * when the queue is empty, with a zero timeout, and then reads whatever is there, so KBSR polling
+ * never blocks. The descriptor is read directly, bypassing std's stdin buffer, which poll can't see.
+ */
+pub struct InputQueue<F: AsFd> {
+    source: F,
+    queue: VecDeque<u8>,
+    closed: bool,
+}
+
+impl<F: AsFd> InputQueue<F> {
+    pub fn new(source: F) -> Self {
+        InputQueue {
+            source,
+            queue: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    pub fn poll(&mut self) -> io::Result<bool> {
+        if self.queue.is_empty() && !self.closed {
+            let mut fds = [PollFd::new(self.source.as_fd(), PollFlags::POLLIN)];
+            /* a hung-up pipe also reports ready, and fill then sees end of file */
+            if poll(&mut fds, PollTimeout::ZERO)? > 0 {
+                self.fill()?;
+            }
+        }
+        Ok(!self.queue.is_empty())
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<u8> {
+        while self.queue.is_empty() && !self.closed {
+            self.fill()?;
+        }
+        self.queue
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "console input closed"))
+    }
+
+    /* one read of up to a buffer's worth; blocks only when nothing is available */
+    fn fill(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 256];
+        loop {
+            match read(self.source.as_fd().as_raw_fd(), &mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(count) => self.queue.extend(&buffer[..count]),
+                Err(Errno::EINTR) => continue,
+                Err(error) => return Err(error.into()),
+            }
+            return Ok(());
+        }
+    }
+}
+
+/* the process's stdin and stdout */
+pub struct StdConsole {
+    input: InputQueue<OwnedFd>,
+}
+
+impl StdConsole {
+    pub fn new() -> io::Result<Self> {
+        Ok(StdConsole {
+            input: InputQueue::new(io::stdin().as_fd().try_clone_to_owned()?),
+        })
+    }
+}
+
+impl Console for StdConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.input.read_byte()
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        self.input.poll()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        io::stdout().write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/* scripted input and captured output; once the input runs out it behaves like a closed stdin */
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.input
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "console input exhausted"))
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.input.is_empty())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/* passes everything through to `inner`, but drops output past `limit` bytes and remembers that it did */
+pub struct LimitedConsole {
+    inner: SharedConsole,
+    limit: Option<u64>,
+    written: u64,
+    overflowed: bool,
+}
+
+impl LimitedConsole {
+    pub fn new(inner: SharedConsole) -> Self {
+        LimitedConsole {
+            inner,
+            limit: None,
+            written: 0,
+            overflowed: false,
+        }
+    }
+
+    /* starts counting again from zero */
+    pub fn set_limit(&mut self, limit: Option<u64>) {
+        self.limit = limit;
+        self.written = 0;
+        self.overflowed = false;
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /* bypasses the limit, for messages that come from the VM rather than the program */
+    pub fn write_unlimited(&mut self, byte: u8) -> io::Result<()> {
+        self.inner.borrow_mut().write_byte(byte)
+    }
+}
+
+impl Console for LimitedConsole {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        self.inner.borrow_mut().read_byte()
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        self.inner.borrow_mut().poll()
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        if self.limit.is_some_and(|limit| self.written >= limit) {
+            self.overflowed = true;
+            return Ok(());
+        }
+        self.written += 1;
+        self.inner.borrow_mut().write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
+/*
+ * A console's input as a reader, for a REPL that shares it with the machine. It takes one byte at a time,
+ * so reading a command line never buffers ahead into keys meant for the program.
+ */
+pub struct ConsoleReader {
+    console: SharedConsole,
+    byte: Option<u8>,
+}
+
+impl ConsoleReader {
+    pub fn new(console: SharedConsole) -> Self {
+        ConsoleReader { console, byte: None }
+    }
+}
+
+impl io::Read for ConsoleReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = io::BufRead::fill_buf(self)?.len().min(buffer.len());
+        if count > 0 {
+            buffer[0] = self.byte.take().unwrap_or_default();
+        }
+        Ok(count)
+    }
+}
+
+impl io::BufRead for ConsoleReader {
+    /* closed input is end of file here rather than an error */
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.byte.is_none() {
+            match self.console.borrow_mut().read_byte() {
+                Ok(byte) => self.byte = Some(byte),
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(self.byte.as_slice())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if amount > 0 {
+            self.byte = None;
+        }
+    }
+}
+
+pub fn std_console() -> SharedConsole {
+    /* only fails when stdin is closed outright, which reads the same as input that has ended */
+    match StdConsole::new() {
+        Ok(console) => Rc::new(RefCell::new(console)),
+        Err(_) => Rc::new(RefCell::new(BufferConsole::new(&[]))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{pipe, write};
+
+    #[test]
+    fn test_buffer_console() {
+        let mut console = BufferConsole::new(b"ab");
+        assert!(console.poll().unwrap());
+        assert_eq!(console.read_byte().unwrap(), b'a');
+        assert_eq!(console.read_byte().unwrap(), b'b');
+        assert!(!console.poll().unwrap());
+        assert_eq!(console.read_byte().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        console.write_byte(b'!').unwrap();
+        assert_eq!(console.output(), b"!");
+    }
+
+    #[test]
+    fn test_console_reader_leaves_the_rest_of_the_input() {
+        let console = Rc::new(RefCell::new(BufferConsole::new(b"step\nkey")));
+        let mut line = String::new();
+        io::BufRead::read_line(&mut ConsoleReader::new(console.clone()), &mut line).unwrap();
+        assert_eq!(line, "step\n");
+        assert_eq!(console.borrow_mut().read_byte().unwrap(), b'k');
+        let mut rest = String::new();
+        io::Read::read_to_string(&mut ConsoleReader::new(console), &mut rest).unwrap();
+        assert_eq!(rest, "ey");
+    }
+
+    #[test]
+    fn test_limited_console() {
+        let inner = Rc::new(RefCell::new(BufferConsole::new(b"")));
+        let mut console = LimitedConsole::new(inner.clone());
+        console.set_limit(Some(2));
+        for byte in b"abc" {
+            console.write_byte(*byte).unwrap();
+        }
+        assert!(console.overflowed());
+        assert_eq!(inner.borrow().output(), b"ab");
+        console.set_limit(None);
+        console.write_byte(b'd').unwrap();
+        assert!(!console.overflowed());
+        assert_eq!(inner.borrow().output(), b"abd");
+    }
+
+    #[test]
+    fn test_input_queue_polls_a_pipe_without_blocking() {
+        let (reader, writer) = pipe().unwrap();
+        let mut input = InputQueue::new(reader);
+        assert!(!input.poll().unwrap());
+        write(&writer, b"hi").unwrap();
+        assert!(input.poll().unwrap());
+        assert_eq!(input.read_byte().unwrap(), b'h');
+        assert!(input.poll().unwrap());
+        assert_eq!(input.read_byte().unwrap(), b'i');
+        assert!(!input.poll().unwrap());
+        drop(writer);
+        assert!(!input.poll().unwrap());
+        assert_eq!(input.read_byte().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_input_queue_read_waits_for_input() {
+        let (reader, writer) = pipe().unwrap();
+        let mut input = InputQueue::new(reader);
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            write(&writer, b"k").unwrap();
+        });
+        assert_eq!(input.read_byte().unwrap(), b'k');
+        sender.join().unwrap();
+    }
+}
@@ -1,269 +1,1007 @@
+use std::error;
+use std::fmt;
 use std::io;
-use std::io::{Write};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::console::{std_console, LimitedConsole, SharedConsole};
+use crate::display::Display;
+use crate::exception::Exception;
 use crate::flag::ConditionFlag;
-use crate::memory::Memory;
+use crate::hook::{with_detached, Hook, SharedHooks};
+use crate::interrupt;
+use crate::interrupt::Interrupt;
+use crate::memory::{Memory, MemoryMappedRegister, MCR_CLOCK_ENABLE};
 use crate::opcode::Opcode;
-use crate::register::{Register, Registers};
-use crate::trap::TrapCode;
-use crate::utils::{get_char_byte, sign_extend};
+use crate::register::{Privilege, Register, Registers, GENERAL_REGISTERS};
+use crate::trace::{TraceEntry, Tracer};
+use crate::watch::{SharedWatches, WatchHit, Watchpoint};
+use crate::trap::{TrapCode, TrapMode};
+use crate::utils::sign_extend;
+
+/* user programs may only touch x3000-xFDFF; below is the OS and vector tables, above are the devices */
+const USER_SPACE_START: u16 = 0x3000;
+const DEVICE_SPACE_START: u16 = 0xFE00;
 
 pub struct CPU {
     memory: Memory,
-    registers: Registers
+    registers: Registers,
+    trap_mode: TrapMode,
+    console: SharedConsole,
+    output: Rc<RefCell<LimitedConsole>>, /* the same console as `console`, for the output limit */
+    limits: Limits,
+    instructions: u64,
+    tracers: Vec<Tracer>,
+    hooks: SharedHooks,     /* also held by memory and registers */
+    watches: SharedWatches, /* likewise */
+    stopped_before: Option<u16>, /* where a before_instruction hook last stopped, so the next step gets past it */
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepOutcome {
+    Continued,              /* executed an ordinary instruction */
+    Halted,                 /* executed the HALT trap */
+    Trapped(TrapCode),      /* executed any other trap */
+    Interrupted(Interrupt), /* entered an interrupt service routine instead of executing */
+    LimitReached(Limit),    /* a run stopped the program for running too long or writing too much */
+    Stopped,                /* a hook asked to stop */
+    Watched(Vec<WatchHit>), /* executed an instruction that hit watchpoints */
+}
+
+/* bounds on one run_until or run_for call; None means unlimited */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub max_output: Option<u64>, /* characters written to the console; the excess is dropped */
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    Instructions,
+    Time,
+    Output,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Instructions => write!(f, "instruction limit reached"),
+            Limit::Time => write!(f, "time limit reached"),
+            Limit::Output => write!(f, "output limit reached"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VmError {
+    pub pc: u16,            /* address of the faulting instruction */
+    pub instruction: u16,
+    pub cause: VmErrorCause,
+}
+
+#[derive(Debug)]
+pub enum VmErrorCause {
+    UnknownTrapCode(u16),
+    UnhandledException(Exception),
+    UnhandledInterrupt(Interrupt),
+    InputClosed,
+    Io(io::Error),
+}
+
+impl From<io::Error> for VmErrorCause {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => VmErrorCause::InputClosed,
+            _ => VmErrorCause::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for VmErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmErrorCause::UnknownTrapCode(trap_code) => write!(f, "unknown trap vector x{:02X}", trap_code),
+            VmErrorCause::UnhandledException(exception) => write!(
+                f,
+                "unhandled {} (no service routine at x{:04X})",
+                exception,
+                exception.table_entry()
+            ),
+            VmErrorCause::UnhandledInterrupt(interrupt) => write!(
+                f,
+                "unhandled interrupt x{:02X} (no service routine at x{:04X})",
+                interrupt.vector,
+                interrupt.table_entry()
+            ),
+            VmErrorCause::InputClosed => write!(f, "input closed while waiting for a key"),
+            VmErrorCause::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl error::Error for VmErrorCause {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VmErrorCause::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/* how an instruction can stop short: an exception the OS should service, or an error for the host */
+enum Fault {
+    Exception(Exception),
+    Error(VmErrorCause),
+}
+
+impl From<VmErrorCause> for Fault {
+    fn from(cause: VmErrorCause) -> Self {
+        Fault::Error(cause)
+    }
+}
+
+impl From<io::Error> for Fault {
+    fn from(error: io::Error) -> Self {
+        Fault::Error(error.into())
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fault at x{:04X} (instruction x{:04X}): {}", self.pc, self.instruction, self.cause)
+    }
+}
+
+impl error::Error for VmError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new()
+    }
 }
 
 impl CPU {
+    /* a CPU on the process's stdin and stdout */
     pub fn new() -> Self {
+        CPU::with_console(std_console(), 0)
+    }
+
+    /* the keyboard, the display (busy for `display_latency` steps per character) and native traps share `console` */
+    pub fn with_console(console: SharedConsole, display_latency: u32) -> Self {
+        let output = Rc::new(RefCell::new(LimitedConsole::new(console)));
+        let console: SharedConsole = output.clone();
+        let hooks = SharedHooks::default();
+        let mut memory = Memory::with_console(console.clone());
+        memory.set_hooks(hooks.clone());
+        let mut registers = Registers::new();
+        registers.set_hooks(hooks.clone());
+        let watches = SharedWatches::default();
+        memory.set_watches(watches.clone());
+        registers.set_watches(watches.clone());
+        memory
+            .attach(Box::new(Display::new(console.clone(), display_latency)))
+            .expect("a new bus only has the keyboard");
         CPU {
-            memory: Memory::new(),
-            registers: Registers::new(),
+            memory,
+            registers,
+            trap_mode: TrapMode::Native,
+            console,
+            output,
+            limits: Limits::default(),
+            instructions: 0,
+            tracers: Vec::new(),
+            hooks,
+            watches,
+            stopped_before: None,
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /* instructions executed so far; interrupt entries and faulting instructions don't count */
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /*
+     * every instruction executed from now on, including ones that raise an exception, is traced. Entering
+     * an interrupt or exception service routine isn't an instruction, so it has no line of its own.
+     */
+    pub fn add_tracer(&mut self, tracer: Tracer) {
+        self.tracers.push(tracer);
+    }
+
+    /* run_until and run_for do this when they return; callers that only step do it themselves */
+    pub fn flush_tracers(&mut self) -> io::Result<()> {
+        for tracer in &mut self.tracers {
+            tracer.flush()?;
         }
+        Ok(())
+    }
+
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.borrow_mut().add(hook);
+    }
+
+    /* by the order the hooks were added; hooks are detached while their own callbacks run, so not from there */
+    pub fn remove_hook(&mut self, index: usize) -> Option<Box<dyn Hook>> {
+        self.hooks.borrow_mut().remove(index)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watches.borrow_mut().add(watchpoint);
+    }
+
+    /* by position in watchpoints() */
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        self.watches.borrow_mut().remove(index)
+    }
+
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.watches.borrow().watchpoints().to_vec()
+    }
+
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
     }
-    
-    pub fn run(&mut self) {
+
+    pub fn reset(&mut self) {
         self.registers.write(Register::COND, ConditionFlag::ZRO as u16);
         self.registers.write(Register::PC, 0x3000u16);
-        loop {
-            let instruction_memory_index = self.registers.read(Register::PC);
-            self.registers.write(Register::PC, instruction_memory_index + 1);
-            self.registers.update_flags(Register::R0);
-            let instruction = self.memory.read(instruction_memory_index);
-            let raw_opcode = instruction >> 12;
-            let opcode = Opcode::from_u16(raw_opcode).unwrap();
-            match opcode {
-                Opcode::BR => {
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let cond_flag = (instruction >> 9) & 0x7;
-                    if cond_flag & self.registers.read(Register::COND) == 1 {
-                        self.registers.write(
-                            Register::PC,
-                            self.registers.read(Register::PC) + pc_offset
-                        )
-                    }
-                },
-                Opcode::ADD => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let raw_sr1 = (instruction >> 6) & 0x7;
-                    let imm_flag = (instruction >> 5) & 0x1;
-                    let dr = Register::from_u16(raw_dr).unwrap();
-                    let sr1 = Register::from_u16(raw_sr1).unwrap();
-                    if imm_flag == 1 {
-                        let imm5 = sign_extend(instruction & 0x1F, 5);
-                        self.registers.write(
-                            dr,
-                            self.registers.read(sr1) + imm5
-                        )
-                    } else {
-                        let raw_sr2 = instruction & 0x7;
-                        let sr2 = Register::from_u16(raw_sr2).unwrap();
-                        self.registers.write(
-                            dr,
-                            self.registers.read(sr1) + self.registers.read(sr2)
-                        )
-                    }
-                    self.registers.update_flags(dr)
-                },
-                Opcode::LD => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let dr = Register::from_u16(raw_dr).unwrap();
+        let mcr = self.memory.peek(MemoryMappedRegister::MCR as u16);
+        /* MCR is plain memory unless a device claims it, so this write cannot fail */
+        let _ = self.memory.write(MemoryMappedRegister::MCR as u16, mcr | MCR_CLOCK_ENABLE);
+    }
+
+    /* runs from wherever PC is; the host sets up the machine, or calls reset first to start at x3000 */
+    pub fn run(&mut self) -> Result<StepOutcome, VmError> {
+        self.run_until(|_| false)
+    }
+
+    /* steps until HALT, a fault, one of the limits, or `stop` returns true before an instruction */
+    pub fn run_until<F: FnMut(&CPU) -> bool>(&mut self, mut stop: F) -> Result<StepOutcome, VmError> {
+        let started = Instant::now();
+        let start_count = self.instructions;
+        self.output.borrow_mut().set_limit(self.limits.max_output);
+        let result = loop {
+            if stop(self) {
+                break Ok(StepOutcome::Continued);
+            }
+            let executed = self.instructions - start_count;
+            if self.limits.max_instructions.is_some_and(|limit| executed >= limit) {
+                break Ok(StepOutcome::LimitReached(Limit::Instructions));
+            }
+            if self.limits.timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                break Ok(StepOutcome::LimitReached(Limit::Time));
+            }
+            match self.step() {
+                Ok(StepOutcome::Halted) => break Ok(StepOutcome::Halted),
+                Err(error) => break Err(error),
+                _ if self.output.borrow().overflowed() => break Ok(StepOutcome::LimitReached(Limit::Output)),
+                Ok(outcome @ (StepOutcome::Stopped | StepOutcome::Watched(_))) => break Ok(outcome),
+                Ok(_) => {},
+            }
+        };
+        self.output.borrow_mut().set_limit(None);
+        /* a trace that can't be written out fails the run rather than coming up short without a word */
+        match (result, self.flush_tracers()) {
+            (Ok(_), Err(error)) => {
+                Err(VmError { pc: self.registers.read(Register::PC), instruction: 0, cause: error.into() })
+            },
+            (result, _) => result,
+        }
+    }
+
+    /* steps at most `count` times; otherwise it is `run_until`, with the same limits and early stops */
+    pub fn run_for(&mut self, count: u64) -> Result<StepOutcome, VmError> {
+        let mut remaining = count;
+        self.run_until(|_| {
+            if remaining == 0 {
+                return true;
+            }
+            remaining -= 1;
+            false
+        })
+    }
+
+    /* takes a pending interrupt if one outranks the current priority, otherwise executes one instruction */
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        /* a stopped clock stays stopped until reset or a write to MCR sets the bit again */
+        if !self.clock_enabled() {
+            return Ok(StepOutcome::Halted);
+        }
+        let instruction_memory_index = self.registers.read(Register::PC);
+        let fault = |instruction, cause| VmError { pc: instruction_memory_index, instruction, cause };
+        let resuming = self.stopped_before.take() == Some(instruction_memory_index);
+        self.watches.borrow_mut().begin(instruction_memory_index);
+        self.memory.tick();
+        let requests = self.memory.pending_interrupts().map_err(|error| fault(0, error.into()))?;
+        if let Some(interrupt) = interrupt::select(&requests, self.registers.priority()) {
+            let outcome = self.interrupt(interrupt).map_err(|cause| fault(0, cause))?;
+            return Ok(self.settle(outcome));
+        }
+        self.registers.write(Register::PC, instruction_memory_index.wrapping_add(1));
+        let mut instruction = match self.read_memory(instruction_memory_index) {
+            Ok(instruction) => instruction,
+            /* a failed fetch has no instruction word to report yet */
+            Err(error) => {
+                let outcome = self.service(error).map_err(|cause| fault(0, cause))?;
+                return Ok(self.settle(outcome));
+            },
+        };
+        /* watchpoints see the instruction's own accesses, not the fetch */
+        self.watches.borrow_mut().begin(instruction_memory_index);
+        let hooked = !self.hooks.borrow().is_empty();
+        if hooked {
+            let hooks = self.hooks.clone();
+            /* the instruction a hook stopped before runs on the next step, even if the hook asks again */
+            let stop = with_detached(&hooks, |hook| {
+                hook.before_instruction(self, instruction_memory_index, &mut instruction)
+            });
+            if stop && !resuming {
+                self.registers.write(Register::PC, instruction_memory_index);
+                self.stopped_before = Some(instruction_memory_index);
+                return Ok(self.settle(StepOutcome::Stopped));
+            }
+        }
+        let tracing = !self.tracers.is_empty();
+        let before = GENERAL_REGISTERS.map(|register| self.registers.read(register));
+        if tracing {
+            self.memory.start_recording();
+        }
+        let result = self.execute(instruction);
+        if result.is_ok() {
+            self.instructions += 1;
+        }
+        if tracing {
+            self.trace(instruction_memory_index, instruction, &before)
+                .map_err(|error| fault(instruction, error.into()))?;
+        }
+        if hooked && result.is_ok() {
+            let hooks = self.hooks.clone();
+            if with_detached(&hooks, |hook| hook.after_instruction(self, instruction_memory_index, instruction)) {
+                self.hooks.borrow_mut().request_stop();
+            }
+        }
+        let outcome = match result {
+            /* whatever ran, clearing the MCR clock-enable bit stops the machine */
+            Ok(_) if !self.clock_enabled() => StepOutcome::Halted,
+            Ok(outcome) => outcome,
+            Err(error) => self.service(error).map_err(|cause| fault(instruction, cause))?,
+        };
+        Ok(self.settle(outcome))
+    }
+
+    /*
+     * ends a step: collects its watchpoint hits and stop requests so none of them leak into the next one.
+     * Hits win over a halt; the stopped clock makes the next step report Halted.
+     */
+    fn settle(&mut self, outcome: StepOutcome) -> StepOutcome {
+        let hits = self.watches.borrow_mut().take_hits();
+        let stop = self.hooks.borrow_mut().take_stop();
+        match outcome {
+            _ if !hits.is_empty() => StepOutcome::Watched(hits),
+            StepOutcome::Halted => outcome,
+            _ if stop => StepOutcome::Stopped,
+            _ => outcome,
+        }
+    }
+
+    fn trace(&mut self, pc: u16, instruction: u16, before: &[u16; 8]) -> io::Result<()> {
+        let after = GENERAL_REGISTERS.map(|register| self.registers.read(register));
+        let entry = TraceEntry {
+            pc,
+            instruction,
+            changes: TraceEntry::diff(before, &after),
+            cond: self.registers.read(Register::COND),
+            accesses: self.memory.take_recording(),
+        };
+        for tracer in &mut self.tracers {
+            tracer.record(&entry)?;
+        }
+        Ok(())
+    }
+
+    fn clock_enabled(&self) -> bool {
+        self.memory.peek(MemoryMappedRegister::MCR as u16) & MCR_CLOCK_ENABLE != 0
+    }
+
+    fn execute(&mut self, instruction: u16) -> Result<StepOutcome, Fault> {
+        let raw_opcode = instruction >> 12;
+        let opcode = Opcode::from_u16(raw_opcode).unwrap();
+        match opcode {
+            Opcode::BR => {
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let cond_flag = (instruction >> 9) & 0x7;
+                if cond_flag & self.registers.read(Register::COND) != 0 {
                     self.registers.write(
-                        dr,
-                        self.memory.read(self.registers.read(Register::PC) + pc_offset)
-                    );
-                    self.registers.update_flags(dr)
-                },
-                Opcode::ST => {
-                    let raw_sr = (instruction >> 9) & 0x7;
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let sr = Register::from_u16(raw_sr).unwrap();
-                    self.memory.write(
-                        self.registers.read(Register::PC) + pc_offset,
-                        self.registers.read(sr)
+                        Register::PC,
+                        self.registers.read(Register::PC).wrapping_add(pc_offset)
                     )
-                },
-                Opcode::JSR => {
-                    let long_flag = (instruction >> 11) & 1;
-                    self.registers.write(Register::R7, self.registers.read(Register::PC));
-                    if long_flag == 1 { /* JSR */
-                        let long_pc_offset = sign_extend(instruction & 0x7FF, 11);
-                        self.registers.write(
-                            Register::PC,
-                            self.registers.read(Register::PC) + long_pc_offset
-                        )
-                    } else { /* JSRR */
-                        let raw_base_r = (instruction >> 6) & 0x7;
-                        let base_r = Register::from_u16(raw_base_r).unwrap();
-                        self.registers.write(
-                            Register::PC,
-                            self.registers.read(base_r)
-                        )
-                    }
-                },
-                Opcode::AND => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let raw_sr1 = (instruction >> 6) & 0x7;
-                    let imm_flag = (instruction >> 5) & 0x1;
-                    let dr = Register::from_u16(raw_dr).unwrap();
-                    let sr1 = Register::from_u16(raw_sr1).unwrap();
-                    if imm_flag == 1 {
-                        let imm5 = sign_extend(instruction & 0x1F, 5);
-                        self.registers.write(
-                            dr,
-                            self.registers.read(sr1) & imm5
-                        )
-                    } else {
-                        let raw_sr2 = instruction & 0x7;
-                        let sr2 = Register::from_u16(raw_sr2).unwrap();
-                        self.registers.write(
-                            dr,
-                            self.registers.read(sr1) & self.registers.read(sr2)
-                        )
-                    }
-                    self.registers.update_flags(dr);
-                },
-                Opcode::LDR => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let raw_base_r = (instruction >> 6) & 0x7;
-                    let offset = sign_extend(instruction & 0x3F, 6);
-                    let dr = Register::from_u16(raw_dr).unwrap();
-                    let base_r = Register::from_u16(raw_base_r).unwrap();
+                }
+            },
+            Opcode::ADD => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let raw_sr1 = (instruction >> 6) & 0x7;
+                let imm_flag = (instruction >> 5) & 0x1;
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let sr1 = Register::from_u16(raw_sr1).unwrap();
+                if imm_flag == 1 {
+                    let imm5 = sign_extend(instruction & 0x1F, 5);
                     self.registers.write(
                         dr,
-                        self.memory.read(self.registers.read(base_r) + offset)
-                    );
-                    self.registers.update_flags(dr);
-                },
-                Opcode::STR => {
-                    let raw_sr = (instruction >> 9) & 0x7;
-                    let raw_base_r = (instruction >> 6) & 0x7;
-                    let offset = sign_extend(instruction & 0x3F, 6);
-                    let sr = Register::from_u16(raw_sr).unwrap();
-                    let base_r = Register::from_u16(raw_base_r).unwrap();
-                    self.memory.write(
-                        self.registers.read(base_r) + offset,
-                        self.registers.read(sr)
+                        self.registers.read(sr1).wrapping_add(imm5)
                     )
-                },
-                Opcode::RTI => {
-                    panic!("RTI is an unused OPCODE")
-                },
-                Opcode::NOT => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let raw_sr = (instruction >> 6) & 0x7;
-                    let dr = Register::from_u16(raw_dr).unwrap();
-                    let sr = Register::from_u16(raw_sr).unwrap();
+                } else {
+                    let raw_sr2 = instruction & 0x7;
+                    let sr2 = Register::from_u16(raw_sr2).unwrap();
                     self.registers.write(
                         dr,
-                        !self.registers.read(sr)
-                    );
-                    self.registers.update_flags(dr)
-                },
-                Opcode::LDI => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let dr = Register::from_u16(raw_dr).unwrap();
-                    let value_index = self.memory.read(self.registers.read(Register::PC) + pc_offset);
+                        self.registers.read(sr1).wrapping_add(self.registers.read(sr2))
+                    )
+                }
+                self.registers.update_flags(dr)
+            },
+            Opcode::LD => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let value = self.read_memory(self.registers.read(Register::PC).wrapping_add(pc_offset))?;
+                self.registers.write(dr, value);
+                self.registers.update_flags(dr)
+            },
+            Opcode::ST => {
+                let raw_sr = (instruction >> 9) & 0x7;
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let sr = Register::from_u16(raw_sr).unwrap();
+                self.write_memory(
+                    self.registers.read(Register::PC).wrapping_add(pc_offset),
+                    self.registers.read(sr)
+                )?
+            },
+            Opcode::JSR => {
+                let long_flag = (instruction >> 11) & 1;
+                self.registers.write(Register::R7, self.registers.read(Register::PC));
+                if long_flag == 1 { /* JSR */
+                    let long_pc_offset = sign_extend(instruction & 0x7FF, 11);
                     self.registers.write(
-                        dr,
-                        self.memory.read(value_index),
-                    );
-                    self.registers.update_flags(dr)
-                },
-                Opcode::STI => {
-                    let raw_sr = (instruction >> 9) & 0x7;
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let sr = Register::from_u16(raw_sr).unwrap();
-                    let key = self.memory.read(self.registers.read(Register::PC) + pc_offset); 
-                    self.memory.write(
-                        key,
-                        self.registers.read(sr)
+                        Register::PC,
+                        self.registers.read(Register::PC).wrapping_add(long_pc_offset)
                     )
-                },
-                Opcode::JMP => {
+                } else { /* JSRR */
                     let raw_base_r = (instruction >> 6) & 0x7;
                     let base_r = Register::from_u16(raw_base_r).unwrap();
                     self.registers.write(
                         Register::PC,
                         self.registers.read(base_r)
                     )
-                },
-                Opcode::RES => {
-                    panic!("RES is an unused OPCODE")
-                },
-                Opcode::LEA => {
-                    let raw_dr = (instruction >> 9) & 0x7;
-                    let pc_offset = sign_extend(instruction & 0x1FF, 9);
-                    let dr = Register::from_u16(raw_dr).unwrap();
+                }
+            },
+            Opcode::AND => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let raw_sr1 = (instruction >> 6) & 0x7;
+                let imm_flag = (instruction >> 5) & 0x1;
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let sr1 = Register::from_u16(raw_sr1).unwrap();
+                if imm_flag == 1 {
+                    let imm5 = sign_extend(instruction & 0x1F, 5);
                     self.registers.write(
                         dr,
-                        self.registers.read(Register::PC) + pc_offset
-                    );
-                    self.registers.update_flags(dr)
-                },
-                Opcode::TRAP => {
+                        self.registers.read(sr1) & imm5
+                    )
+                } else {
+                    let raw_sr2 = instruction & 0x7;
+                    let sr2 = Register::from_u16(raw_sr2).unwrap();
+                    self.registers.write(
+                        dr,
+                        self.registers.read(sr1) & self.registers.read(sr2)
+                    )
+                }
+                self.registers.update_flags(dr);
+            },
+            Opcode::LDR => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let raw_base_r = (instruction >> 6) & 0x7;
+                let offset = sign_extend(instruction & 0x3F, 6);
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let base_r = Register::from_u16(raw_base_r).unwrap();
+                let value = self.read_memory(self.registers.read(base_r).wrapping_add(offset))?;
+                self.registers.write(dr, value);
+                self.registers.update_flags(dr);
+            },
+            Opcode::STR => {
+                let raw_sr = (instruction >> 9) & 0x7;
+                let raw_base_r = (instruction >> 6) & 0x7;
+                let offset = sign_extend(instruction & 0x3F, 6);
+                let sr = Register::from_u16(raw_sr).unwrap();
+                let base_r = Register::from_u16(raw_base_r).unwrap();
+                self.write_memory(
+                    self.registers.read(base_r).wrapping_add(offset),
+                    self.registers.read(sr)
+                )?
+            },
+            Opcode::RTI => {
+                if self.registers.privilege() == Privilege::User {
+                    return Err(Fault::Exception(Exception::PrivilegeViolation));
+                }
+                let pc = self.pop()?;
+                let psr = self.pop()?;
+                self.registers.write(Register::PC, pc);
+                self.registers.set_psr(psr);
+                if psr >> 15 == 1 {
+                    self.registers.enter_user();
+                }
+            },
+            Opcode::NOT => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let raw_sr = (instruction >> 6) & 0x7;
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let sr = Register::from_u16(raw_sr).unwrap();
+                self.registers.write(
+                    dr,
+                    !self.registers.read(sr)
+                );
+                self.registers.update_flags(dr)
+            },
+            Opcode::LDI => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let dr = Register::from_u16(raw_dr).unwrap();
+                let value_index = self.read_memory(self.registers.read(Register::PC).wrapping_add(pc_offset))?;
+                let value = self.read_memory(value_index)?;
+                self.registers.write(dr, value);
+                self.registers.update_flags(dr)
+            },
+            Opcode::STI => {
+                let raw_sr = (instruction >> 9) & 0x7;
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let sr = Register::from_u16(raw_sr).unwrap();
+                let key = self.read_memory(self.registers.read(Register::PC).wrapping_add(pc_offset))?;
+                self.write_memory(
+                    key,
+                    self.registers.read(sr)
+                )?
+            },
+            Opcode::JMP => {
+                let raw_base_r = (instruction >> 6) & 0x7;
+                let base_r = Register::from_u16(raw_base_r).unwrap();
+                self.registers.write(
+                    Register::PC,
+                    self.registers.read(base_r)
+                )
+            },
+            Opcode::RES => {
+                return Err(Fault::Exception(Exception::IllegalOpcode))
+            },
+            Opcode::LEA => {
+                let raw_dr = (instruction >> 9) & 0x7;
+                let pc_offset = sign_extend(instruction & 0x1FF, 9);
+                let dr = Register::from_u16(raw_dr).unwrap();
+                self.registers.write(
+                    dr,
+                    self.registers.read(Register::PC).wrapping_add(pc_offset)
+                );
+                self.registers.update_flags(dr)
+            },
+            Opcode::TRAP => {
+                if self.trap_mode == TrapMode::Native {
                     self.registers.write(
                         Register::R7,
                         self.registers.read(Register::PC)
                     );
-                    let raw_trap_code = instruction & 0xFF;
-                    let trap_code = TrapCode::from_u16(raw_trap_code).unwrap();
-                    match trap_code {
-                        TrapCode::GETC => {
-                            self.registers.write(Register::R0, get_char_byte().unwrap() as u16);
-                            self.registers.update_flags(Register::R0)
-                        },
-                        TrapCode::OUT => {
-                            let char_integer = self.registers.read(Register::R0);
-                            print!("{}", char_integer as u8 as char);
-                            io::stdout().flush().unwrap()
-                        },
-                        TrapCode::PUTS => {
-                            let mut char_mem_idx = self.registers.read(Register::R0);
-                            loop {
-                                let char_integer = self.memory.read(char_mem_idx);
-                                if char_integer == 0 {
-                                    break
-                                }
-                                print!("{}", char_integer as u8 as char);
-                                char_mem_idx += 1;
+                }
+                let mut raw_trap_code = instruction & 0xFF;
+                let hooks = self.hooks.clone();
+                if with_detached(&hooks, |hook| hook.trap(self, &mut raw_trap_code)) {
+                    hooks.borrow_mut().request_stop();
+                }
+                raw_trap_code &= 0xFF;
+                if self.trap_mode == TrapMode::Vectored {
+                    if self.memory.peek(raw_trap_code) == 0 {
+                        return Err(VmErrorCause::UnknownTrapCode(raw_trap_code).into())
+                    }
+                    self.enter_service_routine(raw_trap_code, None)?;
+                    return Ok(match TrapCode::from_u16(raw_trap_code) {
+                        Ok(trap_code) => StepOutcome::Trapped(trap_code),
+                        Err(_) => StepOutcome::Continued,
+                    })
+                }
+                let Ok(trap_code) = TrapCode::from_u16(raw_trap_code) else {
+                    return Err(VmErrorCause::UnknownTrapCode(raw_trap_code).into())
+                };
+                match trap_code {
+                    TrapCode::GETC => {
+                        self.registers.write(Register::R0, self.memory.read_key()? as u16);
+                        self.registers.update_flags(Register::R0)
+                    },
+                    TrapCode::OUT => {
+                        let char_integer = self.registers.read(Register::R0);
+                        self.console.borrow_mut().write_byte(char_integer as u8)?;
+                        self.console.borrow_mut().flush()?
+                    },
+                    TrapCode::PUTS => {
+                        let mut char_mem_idx = self.registers.read(Register::R0);
+                        loop {
+                            let char_integer = self.memory.read(char_mem_idx)?;
+                            if char_integer == 0 {
+                                break
                             }
-                            io::stdout().flush().unwrap()
-                        },
-                        TrapCode::IN => {
-                            print!("Enter a character: ");
-                            io::stdout().flush().unwrap();
-                            let char_byte = get_char_byte().unwrap(); 
-                            print!("{}", char_byte as char);
-                            io::stdout().flush().unwrap();
-                            self.registers.write(Register::R0, char_byte as u16);
-                            self.registers.update_flags(Register::R0)
-                        },
-                        TrapCode::PUTSP => {
-                            let mut char_mem_idx = self.registers.read(Register::R0);
-                            loop {
-                                let char_integer = self.memory.read(char_mem_idx);
-                                if char_integer == 0 {
-                                    break
-                                }
-                                let char_1_integer = char_integer & 0xFF;
-                                print!("{}", char_1_integer as u8 as char);
-                                let char_2_integer = char_integer >> 8;
-                                if char_2_integer != 0 {
-                                    print!("{}", char_2_integer as u8 as char);
-                                }
-                                char_mem_idx += 1;
+                            self.console.borrow_mut().write_byte(char_integer as u8)?;
+                            char_mem_idx = char_mem_idx.wrapping_add(1);
+                        }
+                        self.console.borrow_mut().flush()?
+                    },
+                    TrapCode::IN => {
+                        self.print("Enter a character: ")?;
+                        self.console.borrow_mut().flush()?;
+                        let char_byte = self.memory.read_key()?;
+                        self.console.borrow_mut().write_byte(char_byte)?;
+                        self.console.borrow_mut().flush()?;
+                        self.registers.write(Register::R0, char_byte as u16);
+                        self.registers.update_flags(Register::R0)
+                    },
+                    TrapCode::PUTSP => {
+                        let mut char_mem_idx = self.registers.read(Register::R0);
+                        loop {
+                            let char_integer = self.memory.read(char_mem_idx)?;
+                            if char_integer == 0 {
+                                break
                             }
-                            io::stdout().flush().unwrap()
-                        },
-                        TrapCode::HALT => {
-                            print!("HALT");
-                            io::stdout().flush().unwrap();
-                            break
-                        },
-                    }
-                },
-            }
+                            let char_1_integer = char_integer & 0xFF;
+                            self.console.borrow_mut().write_byte(char_1_integer as u8)?;
+                            let char_2_integer = char_integer >> 8;
+                            if char_2_integer != 0 {
+                                self.console.borrow_mut().write_byte(char_2_integer as u8)?;
+                            }
+                            char_mem_idx = char_mem_idx.wrapping_add(1);
+                        }
+                        self.console.borrow_mut().flush()?
+                    },
+                    TrapCode::HALT => {
+                        /* the VM's own banner, so it doesn't count against the program's output limit */
+                        for byte in b"HALT" {
+                            self.output.borrow_mut().write_unlimited(*byte)?;
+                        }
+                        self.console.borrow_mut().flush()?;
+                        let mcr = self.memory.read(MemoryMappedRegister::MCR as u16)?;
+                        self.memory.write(MemoryMappedRegister::MCR as u16, mcr & !MCR_CLOCK_ENABLE)?;
+                        return Ok(StepOutcome::Halted)
+                    },
+                }
+                return Ok(StepOutcome::Trapped(trap_code))
+            },
+        }
+        Ok(StepOutcome::Continued)
+    }
+
+    fn print(&mut self, text: &str) -> io::Result<()> {
+        let mut console = self.console.borrow_mut();
+        for byte in text.bytes() {
+            console.write_byte(byte)?;
         }
+        Ok(())
+    }
+
+    fn service(&mut self, fault: Fault) -> Result<StepOutcome, VmErrorCause> {
+        match fault {
+            Fault::Exception(exception) => self.raise(exception),
+            Fault::Error(cause) => Err(cause),
+        }
+    }
+
+    /* accesses made on behalf of the running program, which raise ACV in user mode outside user space */
+    fn read_memory(&mut self, address: u16) -> Result<u16, Fault> {
+        self.check_access(address)?;
+        Ok(self.memory.read(address)?)
+    }
+
+    fn write_memory(&mut self, address: u16, value: u16) -> Result<(), Fault> {
+        self.check_access(address)?;
+        Ok(self.memory.write(address, value)?)
+    }
+
+    fn check_access(&self, address: u16) -> Result<(), Fault> {
+        let system_space = !(USER_SPACE_START..DEVICE_SPACE_START).contains(&address);
+        if self.registers.privilege() == Privilege::User && system_space {
+            return Err(Fault::Exception(Exception::AccessViolation));
+        }
+        Ok(())
+    }
+
+    fn raise(&mut self, exception: Exception) -> Result<StepOutcome, VmErrorCause> {
+        if self.memory.peek(exception.table_entry()) == 0 {
+            return Err(VmErrorCause::UnhandledException(exception));
+        }
+        self.enter_service_routine(exception.table_entry(), None)?;
+        Ok(StepOutcome::Continued)
+    }
+
+    fn interrupt(&mut self, interrupt: Interrupt) -> Result<StepOutcome, VmErrorCause> {
+        if self.memory.peek(interrupt.table_entry()) == 0 {
+            return Err(VmErrorCause::UnhandledInterrupt(interrupt));
+        }
+        self.enter_service_routine(interrupt.table_entry(), Some(interrupt.priority))?;
+        Ok(StepOutcome::Interrupted(interrupt))
+    }
+
+    /*
+     * switches to the supervisor stack, pushes PSR then PC and jumps through the vector table entry;
+     * interrupts also raise the priority level to their own. Vectored TRAPs come through here too.
+     */
+    fn enter_service_routine(&mut self, table_entry: u16, priority: Option<u16>) -> io::Result<()> {
+        let psr = self.registers.psr();
+        let pc = self.registers.read(Register::PC);
+        self.registers.enter_supervisor();
+        if let Some(priority) = priority {
+            self.registers.set_psr((priority << 8) | (psr & 0x7));
+        }
+        self.push(psr)?;
+        self.push(pc)?;
+        let address = self.memory.read(table_entry)?;
+        self.registers.write(Register::PC, address);
+        Ok(())
+    }
+
+    fn push(&mut self, value: u16) -> io::Result<()> {
+        let sp = self.registers.read(Register::R6).wrapping_sub(1);
+        self.registers.write(Register::R6, sp);
+        self.memory.write(sp, value)
+    }
+
+    fn pop(&mut self) -> io::Result<u16> {
+        let sp = self.registers.read(Register::R6);
+        let value = self.memory.read(sp)?;
+        self.registers.write(Register::R6, sp.wrapping_add(1));
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+
+    fn cpu_with(source: &str) -> CPU {
+        cpu_with_input(source, b"").0
+    }
+
+    fn cpu_with_input(source: &str, input: &[u8]) -> (CPU, Rc<RefCell<BufferConsole>>) {
+        let program = assemble(source).unwrap();
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let mut cpu = CPU::with_console(console.clone(), 0);
+        cpu.memory_mut().load(program.image.origin, &program.image.words).unwrap();
+        cpu.registers_mut().write(Register::PC, program.image.origin);
+        (cpu, console)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut cpu = cpu_with(".ORIG x3000\nADD R1, R1, #5\nADD R1, R1, #-6\n.END");
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+        assert_eq!(cpu.registers().read(Register::R1), 5);
+        assert_eq!(cpu.registers().read(Register::COND), ConditionFlag::POS as u16);
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+        assert_eq!(cpu.registers().read(Register::R1), 0xFFFF);
+        assert_eq!(cpu.registers().read(Register::COND), ConditionFlag::NEG as u16);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3002);
+    }
+
+    #[test]
+    fn test_run_for_and_run_until() {
+        let mut cpu = cpu_with(
+            ".ORIG x3000
+                 AND R0, R0, #0
+                 ADD R1, R0, #3
+            LOOP ADD R0, R0, #2
+                 ADD R1, R1, #-1
+                 BRp LOOP
+                 HALT
+            .END",
+        );
+        assert_eq!(cpu.run_for(4).unwrap(), StepOutcome::Continued);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3004);
+        let outcome = cpu.run_until(|cpu| cpu.registers().read(Register::R1) == 0).unwrap();
+        assert_eq!(outcome, StepOutcome::Continued);
+        assert_eq!(cpu.registers().read(Register::R0), 6);
+        assert_eq!(cpu.run_for(100).unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3006);
+
+        let mut cpu = cpu_with(".ORIG x3000\nAND R0, R0, #0\nADD R1, R0, #3\nADD R2, R1, #0\nHALT\n.END");
+        cpu.add_watchpoint(Watchpoint::Register(Register::R1));
+        assert!(matches!(cpu.run_for(100).unwrap(), StepOutcome::Watched(_)));
+        assert_eq!(cpu.registers().read(Register::PC), 0x3002);
+
+        let mut cpu = cpu_with(".ORIG x3000\nLOOP BR LOOP\n.END");
+        cpu.set_limits(Limits { max_instructions: Some(10), ..Limits::default() });
+        assert_eq!(cpu.run_for(100).unwrap(), StepOutcome::LimitReached(Limit::Instructions));
+        assert_eq!(cpu.instructions(), 10);
+    }
+
+    #[test]
+    fn test_step_faulted() {
+        let mut cpu = cpu_with(".ORIG x3000\nRES\nTRAP x30\n.END");
+        let error = cpu.step().unwrap_err();
+        assert_eq!((error.pc, error.instruction), (0x3000, 0xD000));
+        assert!(matches!(error.cause, VmErrorCause::UnhandledException(Exception::IllegalOpcode)));
+        let error = cpu.step().unwrap_err();
+        assert_eq!((error.pc, error.instruction), (0x3001, 0xF030));
+        assert!(matches!(error.cause, VmErrorCause::UnknownTrapCode(0x30)));
+        assert_eq!(error.to_string(), "fault at x3001 (instruction xF030): unknown trap vector x30");
+    }
+
+    #[test]
+    fn test_rti_returns_to_user_mode() {
+        let mut cpu = cpu_with(".ORIG x3000\nRTI\n.END");
+        cpu.registers_mut().write(Register::R6, 0x2FFE);
+        cpu.memory_mut().load(0x2FFE, &[0x4000, 0x8001]).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().read(Register::PC), 0x4000);
+        assert_eq!(cpu.registers().privilege(), Privilege::User);
+        assert_eq!(cpu.registers().psr(), 0x8001);
+        assert_eq!(cpu.registers().read(Register::R6), 0);
+    }
+
+    #[test]
+    fn test_rti_in_user_mode_raises_privilege_violation() {
+        let mut cpu = cpu_with(".ORIG x3000\nRTI\n.END");
+        cpu.registers_mut().enter_user();
+        cpu.registers_mut().write(Register::R6, 0xF000);
+        let error = cpu.step().unwrap_err();
+        assert!(matches!(error.cause, VmErrorCause::UnhandledException(Exception::PrivilegeViolation)));
+        assert_eq!(
+            error.to_string(),
+            "fault at x3000 (instruction x8000): unhandled privilege mode violation (no service routine at x0100)"
+        );
+
+        let mut cpu = cpu_with(".ORIG x3000\nRTI\n.END");
+        cpu.memory_mut().write(0x0100, 0x1000).unwrap();
+        cpu.registers_mut().write(Register::COND, ConditionFlag::POS as u16);
+        cpu.registers_mut().write(Register::R6, 0x3000);
+        cpu.registers_mut().enter_user();
+        cpu.registers_mut().write(Register::R6, 0xF000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().read(Register::PC), 0x1000);
+        assert_eq!(cpu.registers().privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.registers().read(Register::R6), 0x2FFE);
+        assert_eq!(cpu.memory().peek(0x2FFE), 0x3001);
+        assert_eq!(cpu.memory().peek(0x2FFF), 0x8001);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        let (mut cpu, _) = cpu_with_input(".ORIG x3000\nADD R0, R0, #1\nLOOP BR LOOP\n.END", b"k");
+        cpu.step().unwrap();
+        let handler = assemble(
+            ".ORIG x1000
+                 AND R2, R2, #0
+                 STI R2, KBSR
+                 LDI R1, KBDR
+                 RTI
+            KBDR .FILL xFE02
+            KBSR .FILL xFE00
+            .END",
+        );
+        cpu.memory_mut().load(0x1000, &handler.unwrap().image.words).unwrap();
+        cpu.memory_mut().write(0x0180, 0x1000).unwrap();
+        cpu.memory_mut().write(0xFE00, 0x4000).unwrap();
+        cpu.registers_mut().write(Register::R6, 0x3000);
+        cpu.registers_mut().enter_user();
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Interrupted(Interrupt { vector: 0x80, priority: 4 }));
+        assert_eq!(cpu.registers().read(Register::PC), 0x1000);
+        assert_eq!(cpu.registers().psr(), 0x0401);
+        assert_eq!(cpu.memory().peek(0x2FFE), 0x3001);
+        assert_eq!(cpu.memory().peek(0x2FFF), 0x8001);
+        cpu.run_for(4).unwrap();
+        assert_eq!(cpu.registers().read(Register::R1), b'k' as u16);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3001);
+        assert_eq!(cpu.registers().psr(), 0x8001);
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+    }
+
+    #[test]
+    fn test_illegal_opcode_and_access_violation() {
+        let mut cpu = cpu_with(".ORIG x3000\nRES\n.END");
+        cpu.memory_mut().write(0x0101, 0x1000).unwrap();
+        cpu.registers_mut().write(Register::R6, 0x3000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().read(Register::PC), 0x1000);
+        assert_eq!(cpu.memory().peek(0x2FFE), 0x3001);
+
+        let mut cpu = cpu_with(".ORIG x3000\nLDI R0, KBSR\nST R0, DATA\nKBSR .FILL xFE00\nDATA .FILL 0\n.END");
+        cpu.memory_mut().write(0x0102, 0x1100).unwrap();
+        cpu.registers_mut().write(Register::R6, 0x3000);
+        cpu.registers_mut().enter_user();
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().read(Register::PC), 0x1100);
+        assert_eq!(cpu.registers().privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.memory().peek(0x2FFE), 0x3001);
+        assert_eq!(cpu.registers().read(Register::R0), 0);
+
+        /* the same accesses are allowed in supervisor mode, and fetching system space is not */
+        let mut cpu = cpu_with(".ORIG x3000\nJMP R1\n.END");
+        cpu.registers_mut().write(Register::R1, 0x0200);
+        cpu.registers_mut().enter_user();
+        cpu.step().unwrap();
+        let error = cpu.step().unwrap_err();
+        assert_eq!(error.pc, 0x0200);
+        assert!(matches!(error.cause, VmErrorCause::UnhandledException(Exception::AccessViolation)));
+    }
+
+    #[test]
+    fn test_clearing_mcr_halts() {
+        let mut cpu = cpu_with(
+            ".ORIG x3000
+                 LDI R0, MCR
+                 LD R1, MASK
+                 AND R0, R0, R1
+                 STI R0, MCR
+                 ADD R2, R2, #1
+            MCR  .FILL xFFFE
+            MASK .FILL x7FFF
+            .END",
+        );
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3004);
+        assert_eq!(cpu.registers().read(Register::R2), 0);
+
+        cpu.reset();
+        assert_eq!(cpu.memory().peek(0xFFFE), 0x8000);
+        let mut cpu = cpu_with(".ORIG x3000\nHALT\nADD R3, R3, #1\nHALT\n.END");
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.memory().peek(0xFFFE), 0x0000);
+        /* nothing runs until the clock is started again */
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::R3), 0);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3001);
+        cpu.memory_mut().write(0xFFFE, 0x8000).unwrap();
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::R3), 1);
+    }
+
+    #[test]
+    fn test_native_traps_use_console() {
+        let (mut cpu, console) = cpu_with_input(
+            ".ORIG x3000
+                 GETC
+                 OUT
+                 IN
+                 LEA R0, HI
+                 PUTS
+                 HALT
+            HI   .STRINGZ \"hi\"
+            .END",
+            b"xy",
+        );
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(console.borrow().output(), b"xEnter a character: yhiHALT");
+        let error = cpu_with_input(".ORIG x3000\nGETC\n.END", b"").0.step().unwrap_err();
+        assert!(matches!(error.cause, VmErrorCause::InputClosed));
     }
 }
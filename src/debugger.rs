@@ -0,0 +1,599 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, Write};
+use crate::assembler::parse_number;
+use crate::cpu::{StepOutcome, VmError, CPU};
+use crate::disassembler::format_line;
+use crate::opcode::Opcode;
+use crate::register::{Privilege, Register, VISIBLE_REGISTERS};
+use crate::symbols::{name_for, SymbolTable};
+use crate::trace::AccessKind;
+use crate::watch::{WatchHit, WatchKind, Watched, Watchpoint};
+
+const HELP: &str = "commands:
+  break <addr|label>      set a breakpoint (b)
+  delete <addr|label>     remove a breakpoint (d)
+  breakpoints             list breakpoints
+  watch <addr|label|reg> [end]  stop after memory is written or a register changes (w)
+  rwatch <addr|label> [end]     stop after memory is read
+  awatch <addr|label> [end]     stop after memory is read or written
+  watches                 list watchpoints
+  unwatch <n>             remove watchpoint n
+  step [n]                execute n instructions (s)
+  next                    step over JSR/JSRR/TRAP (n)
+  finish                  run until the current subroutine returns (RET) or service routine ends (RTI) (fin)
+  continue                run until a breakpoint or HALT (c)
+  regs                    show registers (r)
+  reg <name> <value>      set a register, e.g. `reg R1 x10`
+  mem <addr|label> [n]    show n memory words (m)
+  poke <addr|label> <value>...  write memory words
+  list [addr|label] [n]   disassemble around the PC or an address (l)
+  help                    show this message (h)
+  quit                    leave the debugger (q)";
+
+#[derive(Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+/* why a run command gave control back to the user */
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Watch(Vec<WatchHit>),
+    Halted,
+}
+
+pub struct Debugger {
+    cpu: CPU,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u16>,
+    halted: bool,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU, symbols: SymbolTable) -> Self {
+        Debugger {
+            cpu,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            halted: false,
+        }
+    }
+
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        self.show_location(output)?;
+        write!(output, "(lc3) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            if self.execute(&line?, output)? == Flow::Quit {
+                return Ok(());
+            }
+            write!(output, "(lc3) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    pub fn execute<W: Write>(&mut self, line: &str, output: &mut W) -> io::Result<Flow> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Ok(Flow::Continue);
+        };
+        let result = match command {
+            "break" | "b" => self.set_breakpoint(args, output),
+            "delete" | "d" => self.delete_breakpoint(args, output),
+            "breakpoints" => self.list_breakpoints(output),
+            "watch" | "w" => self.set_watchpoint(WatchKind::Write, args, output),
+            "rwatch" => self.set_watchpoint(WatchKind::Read, args, output),
+            "awatch" => self.set_watchpoint(WatchKind::Access, args, output),
+            "watches" => self.list_watchpoints(output),
+            "unwatch" => self.delete_watchpoint(args, output),
+            "step" | "s" => self.step(args, output),
+            "next" | "n" => self.run(output, |debugger| debugger.next()),
+            "finish" | "fin" => self.run(output, |debugger| debugger.finish()),
+            "continue" | "c" => self.run(output, |debugger| debugger.continue_()),
+            "regs" | "r" => self.show_registers(output),
+            "reg" => self.set_register(args, output),
+            "mem" | "m" => self.show_memory(args, output),
+            "poke" => self.poke(args, output),
+            "list" | "l" => self.list(args, output),
+            "help" | "h" => writeln!(output, "{}", HELP).map_err(DebuggerError::Io),
+            "quit" | "q" => return Ok(Flow::Quit),
+            _ => Err(DebuggerError::Usage(format!("unknown command `{}`, try `help`", command))),
+        };
+        match result {
+            Ok(()) => {},
+            Err(DebuggerError::Io(error)) => return Err(error),
+            Err(DebuggerError::Usage(message)) => writeln!(output, "{}", message)?,
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn resolve(&self, text: &str) -> Result<u16, DebuggerError> {
+        if let Some(&address) = self.symbols.get(text) {
+            return Ok(address);
+        }
+        match parse_number(text) {
+            Some(value) if (-0x8000..=0xFFFF).contains(&value) => Ok(value as u16),
+            _ => Err(DebuggerError::Usage(format!("`{}` is not an address, value or label", text))),
+        }
+    }
+
+    fn set_breakpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let [location] = args else {
+            return Err(DebuggerError::Usage("usage: break <addr|label>".to_string()));
+        };
+        let address = self.resolve(location)?;
+        self.breakpoints.insert(address);
+        writeln!(output, "breakpoint at {}", self.describe(address))?;
+        Ok(())
+    }
+
+    fn delete_breakpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let [location] = args else {
+            return Err(DebuggerError::Usage("usage: delete <addr|label>".to_string()));
+        };
+        let address = self.resolve(location)?;
+        if !self.breakpoints.remove(&address) {
+            return Err(DebuggerError::Usage(format!("no breakpoint at {}", self.describe(address))));
+        }
+        writeln!(output, "deleted breakpoint at {}", self.describe(address))?;
+        Ok(())
+    }
+
+    fn list_breakpoints<W: Write>(&self, output: &mut W) -> Result<(), DebuggerError> {
+        if self.breakpoints.is_empty() {
+            writeln!(output, "no breakpoints")?;
+        }
+        for &address in &self.breakpoints {
+            writeln!(output, "{}", self.describe(address))?;
+        }
+        Ok(())
+    }
+
+    /* a register name only makes a change-watch for `watch`; rwatch and awatch are memory only */
+    fn set_watchpoint<W: Write>(&mut self, kind: WatchKind, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let command = match kind {
+            WatchKind::Read => "rwatch",
+            WatchKind::Write => "watch",
+            WatchKind::Access => "awatch",
+        };
+        let watchpoint = match args {
+            [name] if kind == WatchKind::Write && register_named(name).is_some() => {
+                Watchpoint::Register(register_named(name).unwrap())
+            },
+            [location] => {
+                let address = self.resolve(location)?;
+                Watchpoint::Memory { kind, start: address, end: address }
+            },
+            [start, end] => {
+                let (start, end) = (self.resolve(start)?, self.resolve(end)?);
+                if end < start {
+                    return Err(DebuggerError::Usage(format!("x{:04X} comes before x{:04X}", end, start)));
+                }
+                Watchpoint::Memory { kind, start, end }
+            },
+            _ => return Err(DebuggerError::Usage(format!("usage: {} <addr|label> [end]", command))),
+        };
+        self.cpu.add_watchpoint(watchpoint);
+        writeln!(output, "watchpoint {}: {}", self.cpu.watchpoints().len(), watchpoint)?;
+        Ok(())
+    }
+
+    fn list_watchpoints<W: Write>(&self, output: &mut W) -> Result<(), DebuggerError> {
+        let watchpoints = self.cpu.watchpoints();
+        if watchpoints.is_empty() {
+            writeln!(output, "no watchpoints")?;
+        }
+        for (index, watchpoint) in watchpoints.iter().enumerate() {
+            writeln!(output, "{}: {}", index + 1, watchpoint)?;
+        }
+        Ok(())
+    }
+
+    fn delete_watchpoint<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let [number] = args else {
+            return Err(DebuggerError::Usage("usage: unwatch <n>".to_string()));
+        };
+        let removed = number
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .and_then(|index| self.cpu.remove_watchpoint(index))
+            .ok_or_else(|| DebuggerError::Usage(format!("no watchpoint {}", number)))?;
+        writeln!(output, "deleted watchpoint {}", removed)?;
+        Ok(())
+    }
+
+    fn step<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let count = match args {
+            [] => 1,
+            [count] => count
+                .parse::<u64>()
+                .map_err(|_| DebuggerError::Usage("usage: step [n]".to_string()))?,
+            _ => return Err(DebuggerError::Usage("usage: step [n]".to_string())),
+        };
+        self.run(output, |debugger| {
+            for remaining in (0..count).rev() {
+                match debugger.single_step()? {
+                    Stop::Stepped => {},
+                    stop => return Ok(stop),
+                }
+                if remaining > 0 && debugger.at_breakpoint() {
+                    return Ok(Stop::Breakpoint);
+                }
+            }
+            Ok(Stop::Stepped)
+        })
+    }
+
+    /* runs one of the execution commands and reports where it stopped */
+    fn run<W: Write, F>(&mut self, output: &mut W, command: F) -> Result<(), DebuggerError>
+    where
+        F: FnOnce(&mut Self) -> Result<Stop, VmError>,
+    {
+        if self.halted {
+            return Err(DebuggerError::Usage("the program has halted".to_string()));
+        }
+        let stop = command(self);
+        self.cpu.flush_tracers()?;
+        match stop {
+            Ok(Stop::Halted) => {
+                self.halted = true;
+                writeln!(output, "\nprogram halted")?;
+            },
+            Ok(Stop::Breakpoint) => {
+                let pc = self.cpu.registers().read(Register::PC);
+                writeln!(output, "breakpoint at {}", self.describe(pc))?;
+            },
+            Ok(Stop::Watch(hits)) => {
+                for hit in hits {
+                    writeln!(output, "watchpoint: {}", self.describe_hit(&hit))?;
+                }
+            },
+            Ok(Stop::Stepped) => {},
+            Err(error) => writeln!(output, "\n{}", error)?,
+        }
+        self.show_location(output)?;
+        Ok(())
+    }
+
+    fn single_step(&mut self) -> Result<Stop, VmError> {
+        match self.cpu.step()? {
+            StepOutcome::Halted => Ok(Stop::Halted),
+            StepOutcome::Watched(hits) => Ok(Stop::Watch(hits)),
+            _ => Ok(Stop::Stepped),
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.cpu.registers().read(Register::PC))
+    }
+
+    fn continue_(&mut self) -> Result<Stop, VmError> {
+        loop {
+            match self.single_step()? {
+                Stop::Stepped => {},
+                stop => return Ok(stop),
+            }
+            if self.at_breakpoint() {
+                return Ok(Stop::Breakpoint);
+            }
+        }
+    }
+
+    /* a TRAP runs until PC comes back to the instruction after it; with the OS that is after the RTI */
+    fn next(&mut self) -> Result<Stop, VmError> {
+        let pc = self.cpu.registers().read(Register::PC);
+        let opcode = Opcode::from_u16(self.current_instruction() >> 12);
+        let stop = self.single_step()?;
+        if !matches!(stop, Stop::Stepped) || self.at_breakpoint() {
+            return Ok(stop);
+        }
+        match opcode {
+            Ok(Opcode::JSR) => self.finish(),
+            Ok(Opcode::TRAP) => self.run_to(pc.wrapping_add(1)),
+            _ => Ok(stop),
+        }
+    }
+
+    fn run_to(&mut self, address: u16) -> Result<Stop, VmError> {
+        while self.cpu.registers().read(Register::PC) != address {
+            match self.single_step()? {
+                Stop::Stepped => {},
+                stop => return Ok(stop),
+            }
+            if self.at_breakpoint() {
+                return Ok(Stop::Breakpoint);
+            }
+        }
+        Ok(Stop::Stepped)
+    }
+
+    /*
+     * JSR/JSRR nest one level deeper, RET (JMP R7) leaves one; stop after the RET that leaves this level.
+     * Started inside a service routine, also stop after its RTI: the one run in supervisor mode with the
+     * stack no deeper than where we started, since nested TRAPs and interrupts push a frame below it.
+     */
+    fn finish(&mut self) -> Result<Stop, VmError> {
+        let mut depth = 0;
+        let in_routine = self.cpu.registers().privilege() == Privilege::Supervisor;
+        let stack = self.cpu.registers().read(Register::R6);
+        loop {
+            let instruction = self.current_instruction();
+            let opcode = Opcode::from_u16(instruction >> 12).unwrap();
+            let is_return = opcode == Opcode::JMP && (instruction >> 6) & 0x7 == Register::R7 as u16;
+            let registers = self.cpu.registers();
+            let leaves_routine = in_routine
+                && opcode == Opcode::RTI
+                && registers.privilege() == Privilege::Supervisor
+                && registers.read(Register::R6) >= stack;
+            if opcode == Opcode::JSR {
+                depth += 1;
+            } else if (is_return || leaves_routine) && depth == 0 {
+                return self.single_step();
+            } else if is_return {
+                depth -= 1;
+            }
+            match self.single_step()? {
+                Stop::Stepped => {},
+                stop => return Ok(stop),
+            }
+            if self.at_breakpoint() {
+                return Ok(Stop::Breakpoint);
+            }
+        }
+    }
+
+    fn current_instruction(&self) -> u16 {
+        self.cpu.memory().peek(self.cpu.registers().read(Register::PC))
+    }
+
+    fn show_registers<W: Write>(&self, output: &mut W) -> Result<(), DebuggerError> {
+        let registers = self.cpu.registers();
+        for (index, &register) in VISIBLE_REGISTERS.iter().enumerate() {
+            let separator = if index % 4 == 3 || index == VISIBLE_REGISTERS.len() - 1 { "\n" } else { "  " };
+            let value = registers.read(register);
+            if register == Register::COND {
+                let flag = match value {
+                    4 => "N",
+                    2 => "Z",
+                    1 => "P",
+                    _ => "?",
+                };
+                write!(output, "{:<4} {}{}", "COND", flag, separator)?;
+            } else {
+                write!(output, "{:<4} x{:04X}{}", format!("{:?}", register), value, separator)?;
+            }
+        }
+        writeln!(
+            output,
+            "PSR  x{:04X} ({:?} mode, priority {})",
+            registers.psr(),
+            registers.privilege(),
+            registers.priority()
+        )?;
+        Ok(())
+    }
+
+    fn set_register<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let [name, value] = args else {
+            return Err(DebuggerError::Usage("usage: reg <name> <value>".to_string()));
+        };
+        let register = register_named(name).ok_or_else(|| DebuggerError::Usage(format!("unknown register `{}`", name)))?;
+        let value = self.resolve(value)?;
+        self.cpu.registers_mut().write(register, value);
+        writeln!(output, "{:?} = x{:04X}", register, value)?;
+        Ok(())
+    }
+
+    fn show_memory<W: Write>(&self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let (start, count) = match args {
+            [location] => (self.resolve(location)?, 1),
+            [location, count] => (self.resolve(location)?, self.resolve(count)?),
+            _ => return Err(DebuggerError::Usage("usage: mem <addr|label> [n]".to_string())),
+        };
+        for offset in 0..count {
+            let address = start.wrapping_add(offset);
+            let value = self.cpu.memory().peek(address);
+            let printable = match value {
+                0x20..=0x7E => format!("  '{}'", value as u8 as char),
+                _ => String::new(),
+            };
+            writeln!(output, "x{:04X}  x{:04X}  #{}{}", address, value, value as i16, printable)?;
+        }
+        Ok(())
+    }
+
+    fn poke<W: Write>(&mut self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let Some((location, values)) = args.split_first().filter(|(_, values)| !values.is_empty()) else {
+            return Err(DebuggerError::Usage("usage: poke <addr|label> <value>...".to_string()));
+        };
+        let start = self.resolve(location)?;
+        let values = values
+            .iter()
+            .map(|value| self.resolve(value))
+            .collect::<Result<Vec<u16>, DebuggerError>>()?;
+        for (offset, value) in values.iter().enumerate() {
+            self.cpu.memory_mut().write(start.wrapping_add(offset as u16), *value)?;
+        }
+        writeln!(output, "wrote {} word(s) at {}", values.len(), self.describe(start))?;
+        Ok(())
+    }
+
+    fn list<W: Write>(&self, args: &[&str], output: &mut W) -> Result<(), DebuggerError> {
+        let pc = self.cpu.registers().read(Register::PC);
+        let (start, count) = match args {
+            [] => (pc.wrapping_sub(3), 8),
+            [location] => (self.resolve(location)?, 8),
+            [location, count] => (self.resolve(location)?, self.resolve(count)?),
+            _ => return Err(DebuggerError::Usage("usage: list [addr|label] [n]".to_string())),
+        };
+        for offset in 0..count {
+            self.write_line(start.wrapping_add(offset), output)?;
+        }
+        Ok(())
+    }
+
+    fn show_location<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        self.write_line(self.cpu.registers().read(Register::PC), output)
+    }
+
+    /* `=>` marks the PC and `*` a breakpoint */
+    fn write_line<W: Write>(&self, address: u16, output: &mut W) -> io::Result<()> {
+        let pc = self.cpu.registers().read(Register::PC);
+        let marker = if address == pc { "=>" } else { "  " };
+        let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+        let word = self.cpu.memory().peek(address);
+        writeln!(output, "{}{} {}", marker, breakpoint, format_line(address, word, Some(&self.symbols)))
+    }
+
+    /* write x3006 <COPY>: x0000 -> x0005 at x3002 */
+    fn describe_hit(&self, hit: &WatchHit) -> String {
+        let change = format!("x{:04X} -> x{:04X} at {}", hit.old, hit.new, self.describe(hit.pc));
+        match hit.watched {
+            Watched::Memory(AccessKind::Read, address) => {
+                format!("read {}: x{:04X} at {}", self.describe(address), hit.new, self.describe(hit.pc))
+            },
+            Watched::Memory(AccessKind::Write, address) => format!("write {}: {}", self.describe(address), change),
+            Watched::Register(register) => format!("{:?}: {}", register, change),
+        }
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match name_for(&self.symbols, address) {
+            Some(name) => format!("x{:04X} <{}>", address, name),
+            None => format!("x{:04X}", address),
+        }
+    }
+}
+
+fn register_named(name: &str) -> Option<Register> {
+    VISIBLE_REGISTERS
+        .iter()
+        .copied()
+        .find(|register| format!("{:?}", register).eq_ignore_ascii_case(name))
+}
+
+enum DebuggerError {
+    Usage(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for DebuggerError {
+    fn from(error: io::Error) -> Self {
+        DebuggerError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::tests::test_machine;
+
+    const PROGRAM: &str = ".ORIG x3000
+         AND R0, R0, #0
+         JSR TWICE
+         JSR TWICE
+    DONE HALT
+   TWICE ADD R0, R0, #1
+         ADD R0, R0, #1
+         RET
+    .END";
+
+    fn debugger() -> Debugger {
+        let (cpu, _, symbols) = test_machine(PROGRAM, b"", |machine| machine);
+        Debugger::new(cpu, symbols)
+    }
+
+    fn run(debugger: &mut Debugger, commands: &[&str]) -> String {
+        let mut output = Vec::new();
+        for command in commands {
+            debugger.execute(command, &mut output).unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    fn register(debugger: &Debugger, register: Register) -> u16 {
+        debugger.cpu.registers().read(register)
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut debugger = debugger();
+        let output = run(&mut debugger, &["break TWICE", "continue"]);
+        assert!(output.contains("breakpoint at x3004 <TWICE>"));
+        assert_eq!(register(&debugger, Register::PC), 0x3004);
+        run(&mut debugger, &["delete TWICE", "continue"]);
+        assert_eq!(register(&debugger, Register::R0), 4);
+        assert!(run(&mut debugger, &["step"]).contains("the program has halted"));
+    }
+
+    #[test]
+    fn test_next_and_finish() {
+        let mut debugger = debugger();
+        run(&mut debugger, &["step", "next"]);
+        assert_eq!(register(&debugger, Register::PC), 0x3002);
+        assert_eq!(register(&debugger, Register::R0), 2);
+        run(&mut debugger, &["step", "finish"]);
+        assert_eq!(register(&debugger, Register::PC), 0x3003);
+        assert_eq!(register(&debugger, Register::R0), 4);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+        let output = run(&mut debugger, &["watch R0", "rwatch x4000 x400F", "watches", "continue"]);
+        assert!(output.contains("1: change R0\n2: Read x4000-x400F\n"));
+        assert!(output.contains("watchpoint: R0: x0000 -> x0001 at x3004 <TWICE>"));
+        assert_eq!(register(&debugger, Register::PC), 0x3005);
+        let output = run(&mut debugger, &["unwatch 1", "continue"]);
+        assert!(output.contains("deleted watchpoint change R0"));
+        assert!(output.contains("program halted"));
+        assert_eq!(register(&debugger, Register::R0), 4);
+    }
+
+    #[test]
+    fn test_next_and_finish_with_the_os() {
+        let source = ".ORIG x3000\nLD R0, BANG\nOUT\nOUT\nHALT\nBANG .FILL x21\n.END";
+        let (cpu, console, symbols) = test_machine(source, b"", |machine| machine.os(true));
+        let mut debugger = Debugger::new(cpu, symbols);
+        run(&mut debugger, &["break x3000", "continue", "step", "next"]);
+        assert_eq!(register(&debugger, Register::PC), 0x3002);
+        assert_eq!(console.borrow().output(), b"!");
+        run(&mut debugger, &["step", "step", "finish"]);
+        assert_eq!(register(&debugger, Register::PC), 0x3003);
+        assert_eq!(console.borrow().output(), b"!!");
+        assert_eq!(debugger.cpu.registers().privilege(), Privilege::User);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut debugger = debugger();
+        let output = run(&mut debugger, &["reg r3 x41", "poke x4000 #72 x69", "mem x4000 2", "regs"]);
+        assert_eq!(register(&debugger, Register::R3), 0x41);
+        assert!(output.contains("x4000  x0048  #72  'H'\nx4001  x0069  #105  'i'\n"));
+        assert!(output.contains("R3   x0041"));
+        assert!(output.contains("COND Z"));
+    }
+
+    #[test]
+    fn test_list_marks_pc_and_breakpoints() {
+        let mut debugger = debugger();
+        let output = run(&mut debugger, &["b DONE", "list x3000 4"]);
+        assert!(output.contains("=>  x3000  5020               AND R0, R0, #0"));
+        assert!(output.contains("  * x3003  F025  DONE         HALT"));
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        let mut debugger = debugger();
+        assert!(run(&mut debugger, &["frobnicate"]).contains("unknown command"));
+        assert!(run(&mut debugger, &["break NOWHERE"]).contains("not an address"));
+        assert!(run(&mut debugger, &["rwatch R0"]).contains("not an address"));
+        assert!(run(&mut debugger, &["unwatch 3"]).contains("no watchpoint 3"));
+        assert_eq!(debugger.execute("quit", &mut Vec::new()).unwrap(), Flow::Quit);
+    }
+}
@@ -0,0 +1,41 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use crate::interrupt::Interrupt;
+
+/*
+ * A memory-mapped peripheral. Memory hands every access inside the claimed range to the device
+ * instead of RAM; peek must not have side effects so debuggers and disassembly can look safely.
+ */
+pub trait Device {
+    fn range(&self) -> RangeInclusive<u16>;
+    fn read(&mut self, address: u16) -> io::Result<u16>;
+    fn peek(&self, address: u16) -> u16;
+    fn write(&mut self, address: u16, value: u16) -> io::Result<()>;
+
+    /* polled before every instruction; the CPU takes the request if it outranks the current priority */
+    fn interrupt(&mut self) -> io::Result<Option<Interrupt>> {
+        Ok(None)
+    }
+
+    /* called once per CPU step, for devices that keep time */
+    fn tick(&mut self) {}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeviceError {
+    Overlap { start: u16, end: u16 },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Overlap { start, end } => {
+                write!(f, "device range x{:04X}-x{:04X} overlaps another device", start, end)
+            },
+        }
+    }
+}
+
+impl error::Error for DeviceError {}
@@ -0,0 +1,172 @@
+use crate::memory::Memory;
+use crate::opcode::Opcode;
+use crate::register::Register;
+use crate::symbols::{name_for, SymbolTable};
+use crate::trap::TrapCode;
+use crate::utils::sign_extend;
+
+/* decodes one word the same way CPU::run does; words that are not a valid encoding become .FILL */
+pub fn disassemble(address: u16, instruction: u16, symbols: Option<&SymbolTable>) -> String {
+    let fill = format!(".FILL x{:04X}", instruction);
+    let register = |shift: u16| format!("{:?}", Register::from_u16((instruction >> shift) & 0x7).unwrap());
+    let target = |offset: u16| {
+        let target = address.wrapping_add(1).wrapping_add(offset);
+        match symbols.and_then(|symbols| name_for(symbols, target)) {
+            Some(name) => name.to_string(),
+            None => format!("x{:04X}", target),
+        }
+    };
+    let signed = |value: u16| format!("#{}", value as i16);
+    let opcode = Opcode::from_u16(instruction >> 12).unwrap();
+    match opcode {
+        Opcode::BR => {
+            let cond_flag = (instruction >> 9) & 0x7;
+            if cond_flag == 0 {
+                return fill;
+            }
+            let flags: String = [(0x4, 'n'), (0x2, 'z'), (0x1, 'p')]
+                .iter()
+                .filter(|(bit, _)| cond_flag & bit != 0)
+                .map(|(_, flag)| *flag)
+                .collect();
+            let flags = if cond_flag == 0x7 { String::new() } else { flags };
+            format!("BR{} {}", flags, target(sign_extend(instruction & 0x1FF, 9)))
+        },
+        Opcode::ADD | Opcode::AND => {
+            let name = if opcode == Opcode::ADD { "ADD" } else { "AND" };
+            let imm_flag = (instruction >> 5) & 0x1;
+            if imm_flag == 1 {
+                let imm5 = sign_extend(instruction & 0x1F, 5);
+                format!("{} {}, {}, {}", name, register(9), register(6), signed(imm5))
+            } else if (instruction >> 3) & 0x3 == 0 {
+                format!("{} {}, {}, {}", name, register(9), register(6), register(0))
+            } else {
+                fill
+            }
+        },
+        Opcode::LD | Opcode::ST | Opcode::LDI | Opcode::STI | Opcode::LEA => {
+            let pc_offset = sign_extend(instruction & 0x1FF, 9);
+            format!("{:?} {}, {}", opcode, register(9), target(pc_offset))
+        },
+        Opcode::JSR => {
+            let long_flag = (instruction >> 11) & 1;
+            if long_flag == 1 {
+                format!("JSR {}", target(sign_extend(instruction & 0x7FF, 11)))
+            } else if instruction & 0x0E3F == 0 {
+                format!("JSRR {}", register(6))
+            } else {
+                fill
+            }
+        },
+        Opcode::LDR | Opcode::STR => {
+            let offset = sign_extend(instruction & 0x3F, 6);
+            format!("{:?} {}, {}, {}", opcode, register(9), register(6), signed(offset))
+        },
+        Opcode::RTI if instruction & 0x0FFF == 0 => "RTI".to_string(),
+        Opcode::NOT if instruction & 0x3F == 0x3F => format!("NOT {}, {}", register(9), register(6)),
+        Opcode::JMP if instruction & 0x0E3F == 0 => {
+            let raw_base_r = (instruction >> 6) & 0x7;
+            if raw_base_r == Register::R7 as u16 {
+                "RET".to_string()
+            } else {
+                format!("JMP {}", register(6))
+            }
+        },
+        Opcode::TRAP if instruction & 0x0F00 == 0 => {
+            let raw_trap_code = instruction & 0xFF;
+            match TrapCode::from_u16(raw_trap_code) {
+                Ok(trap_code) => format!("{:?}", trap_code),
+                Err(_) => format!("TRAP x{:02X}", raw_trap_code),
+            }
+        },
+        Opcode::RTI | Opcode::NOT | Opcode::JMP | Opcode::RES | Opcode::TRAP => fill,
+    }
+}
+
+/* one listing line: address, raw word, label defined there (if any) and the decoded text */
+pub fn format_line(address: u16, instruction: u16, symbols: Option<&SymbolTable>) -> String {
+    let label = symbols.and_then(|symbols| name_for(symbols, address)).unwrap_or("");
+    format!(
+        "x{:04X}  {:04X}  {:<12} {}",
+        address,
+        instruction,
+        label,
+        disassemble(address, instruction, symbols)
+    )
+    .trim_end()
+    .to_string()
+}
+
+/* lists the inclusive range start..=end without triggering memory-mapped devices */
+pub fn disassemble_memory(memory: &Memory, start: u16, end: u16, symbols: Option<&SymbolTable>) -> Vec<String> {
+    (start..=end)
+        .map(|address| format_line(address, memory.peek(address), symbols))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let source = ".ORIG x3000
+            LOOP BRnz LOOP
+                 BR LOOP
+                 ADD R1, R2, R3
+                 AND R1, R2, #-1
+                 LD R0, DATA
+                 STI R0, DATA
+                 JSR LOOP
+                 JSRR R4
+                 LDR R1, R6, #-32
+                 STR R1, R6, #31
+                 RTI
+                 NOT R2, R3
+                 JMP R2
+                 RET
+                 LEA R0, LOOP
+                 PUTS
+                 TRAP x30
+            DATA .FILL xD000
+            .END";
+        let program = assemble(source).unwrap();
+        let lines: Vec<String> = program
+            .image
+            .words
+            .iter()
+            .enumerate()
+            .map(|(index, &word)| disassemble(0x3000 + index as u16, word, Some(&program.symbols)))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "BRnz LOOP", "BR LOOP", "ADD R1, R2, R3", "AND R1, R2, #-1", "LD R0, DATA",
+                "STI R0, DATA", "JSR LOOP", "JSRR R4", "LDR R1, R6, #-32", "STR R1, R6, #31",
+                "RTI", "NOT R2, R3", "JMP R2", "RET", "LEA R0, LOOP", "PUTS", "TRAP x30",
+                ".FILL xD000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_without_symbols() {
+        assert_eq!(disassemble(0x3000, 0x0FFE, None), "BR x2FFF");
+        assert_eq!(disassemble(0x3000, 0x0000, None), ".FILL x0000");
+        assert_eq!(disassemble(0x3000, 0x1288, None), ".FILL x1288");
+        assert_eq!(disassemble(0x3000, 0x9280, None), ".FILL x9280");
+    }
+
+    #[test]
+    fn test_disassemble_memory() {
+        let mut memory = Memory::new();
+        memory.load(0x3000, &[0x5260, 0xF025]).unwrap();
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN".to_string(), 0x3000);
+        assert_eq!(
+            disassemble_memory(&memory, 0x3000, 0x3001, Some(&symbols)),
+            vec!["x3000  5260  MAIN         AND R1, R1, #0", "x3001  F025               HALT"]
+        );
+    }
+}
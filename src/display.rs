@@ -0,0 +1,81 @@
+use std::io;
+use std::ops::RangeInclusive;
+use crate::console::SharedConsole;
+use crate::device::Device;
+use crate::memory::MemoryMappedRegister;
+
+const DSR_READY: u16 = 1 << 15;
+
+/*
+ * Writing DDR prints its low byte and keeps DSR not-ready for `latency` CPU steps. A character written
+ * while the display is still busy is dropped, the way a program that skips the DSR check would lose it.
+ */
+pub struct Display {
+    console: SharedConsole,
+    latency: u32,
+    busy: u32,
+    data: u16,
+}
+
+impl Display {
+    pub fn new(console: SharedConsole, latency: u32) -> Self {
+        Display { console, latency, busy: 0, data: 0 }
+    }
+}
+
+impl Device for Display {
+    fn range(&self) -> RangeInclusive<u16> {
+        MemoryMappedRegister::DSR as u16..=MemoryMappedRegister::DDR as u16 + 1
+    }
+
+    fn read(&mut self, address: u16) -> io::Result<u16> {
+        Ok(self.peek(address))
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            address if address == MemoryMappedRegister::DSR as u16 && self.busy == 0 => DSR_READY,
+            address if address == MemoryMappedRegister::DDR as u16 => self.data,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u16) -> io::Result<()> {
+        if address != MemoryMappedRegister::DDR as u16 || self.busy != 0 {
+            return Ok(());
+        }
+        self.data = value;
+        self.busy = self.latency;
+        let mut console = self.console.borrow_mut();
+        console.write_byte(value as u8)?;
+        console.flush()
+    }
+
+    fn tick(&mut self) {
+        self.busy = self.busy.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn test_display_latency() {
+        let console = Rc::new(RefCell::new(BufferConsole::new(b"")));
+        let mut display = Display::new(console.clone(), 2);
+        assert_eq!(display.read(0xFE04).unwrap(), DSR_READY);
+        display.write(0xFE06, b'o' as u16).unwrap();
+        assert_eq!(display.read(0xFE04).unwrap(), 0);
+        display.write(0xFE06, b'x' as u16).unwrap();
+        display.tick();
+        assert_eq!(display.read(0xFE04).unwrap(), 0);
+        display.tick();
+        assert_eq!(display.read(0xFE04).unwrap(), DSR_READY);
+        display.write(0xFE06, b'k' as u16).unwrap();
+        assert_eq!(console.borrow().output(), b"ok");
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+
+/* the exception vectors the ISA defines; their service routine addresses live at x0100 + vector */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Exception {
+    PrivilegeViolation = 0x00,
+    IllegalOpcode = 0x01,
+    AccessViolation = 0x02,
+}
+
+impl Exception {
+    pub fn vector(self) -> u16 {
+        self as u16
+    }
+
+    pub fn table_entry(self) -> u16 {
+        0x0100 + self.vector()
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exception::PrivilegeViolation => write!(f, "privilege mode violation"),
+            Exception::IllegalOpcode => write!(f, "illegal opcode"),
+            Exception::AccessViolation => write!(f, "access control violation"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exception_table_entry() {
+        assert_eq!(Exception::PrivilegeViolation.table_entry(), 0x0100);
+        assert_eq!(Exception::IllegalOpcode.table_entry(), 0x0101);
+        assert_eq!(Exception::AccessViolation.table_entry(), 0x0102);
+    }
+}
@@ -0,0 +1,517 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::os::fd::AsFd;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use crate::cpu::{StepOutcome, CPU};
+use crate::register::{Register, VISIBLE_REGISTERS};
+
+/*
+ * GDB remote serial protocol stub. The LC-3 is word addressed, so memory packets use word addresses
+ * but, as GDB expects, byte counts (`m3000,4` reads x3000 and x3001). Every word or register is sent
+ * as four hex digits, big-endian like .obj images.
+ */
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="cond" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+/* the PacketSize we advertise, in bytes of packet data */
+const MAX_PACKET: usize = 0x1000;
+
+/* how many instructions `c` runs between checks for a Ctrl-C from the client */
+const INTERRUPT_POLL_INTERVAL: u32 = 1024;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub<'a, S> {
+    cpu: &'a mut CPU,
+    stream: S,
+    breakpoints: BTreeSet<u16>,
+    ack: bool,
+    halted: bool,
+    pending: VecDeque<u8>, /* bytes that arrived while checking for Ctrl-C or waiting for an ack */
+}
+
+impl<'a, S: Read + Write + AsFd> GdbStub<'a, S> {
+    pub fn new(cpu: &'a mut CPU, stream: S) -> Self {
+        GdbStub {
+            cpu,
+            stream,
+            breakpoints: BTreeSet::new(),
+            ack: true,
+            halted: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /* serves packets until the client kills, detaches or disconnects */
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet)? {
+                Some(response) => self.send(&response)?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut buffer = [0; 1];
+        match self.stream.read(&mut buffer)? {
+            0 => Ok(None),
+            _ => Ok(Some(buffer[0])),
+        }
+    }
+
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            /* skip acks and stray interrupts between packets */
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'$') => break,
+                    Some(_) => {},
+                }
+            }
+            /* keep at most one byte past MAX_PACKET so handle can reject oversized packets */
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        sum = sum.wrapping_add(byte);
+                        if data.len() <= MAX_PACKET {
+                            data.push(byte);
+                        }
+                    },
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if expected == Some(sum) {
+                if self.ack {
+                    self.stream.write_all(b"+")?;
+                }
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            if self.ack {
+                self.stream.write_all(b"-")?;
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            self.stream.flush()?;
+            if !self.ack || self.read_ack()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /*
+     * waits for the client's `+` (true) or `-` (false, resend). Anything else, such as a pipelined packet
+     * or a Ctrl-C, is kept for read_packet and interrupted. A closed stream counts as acked.
+     */
+    fn read_ack(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 1];
+        loop {
+            match self.stream.read(&mut buffer)? {
+                0 => return Ok(true),
+                _ if buffer[0] == b'+' => return Ok(true),
+                _ if buffer[0] == b'-' => return Ok(false),
+                _ => self.pending.push_back(buffer[0]),
+            }
+        }
+    }
+
+    /* returns the reply to send, or None when the session is over */
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        if packet.len() > MAX_PACKET {
+            return Ok(Some("E01".to_string()));
+        }
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(SIGTRAP),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.set_breakpoint(&packet[1..], true),
+            Some(b'z') => self.set_breakpoint(&packet[1..], false),
+            Some(b's') => self.resume(true)?,
+            Some(b'c') => self.resume(false)?,
+            Some(b'H') => "OK".to_string(),
+            Some(b'k') => return Ok(None),
+            Some(b'D') => {
+                self.send("OK")?;
+                return Ok(None);
+            },
+            Some(b'q') | Some(b'Q') => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+", MAX_PACKET);
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_pair(range, ',') {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, &TARGET_XML[offset..end])
+                },
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        if self.halted {
+            return "W00".to_string();
+        }
+        format!("S{:02x}", signal)
+    }
+
+    fn read_registers(&self) -> String {
+        VISIBLE_REGISTERS
+            .iter()
+            .map(|&register| format!("{:04x}", self.cpu.registers().read(register)))
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        match parse_words(data) {
+            Some(values) if values.len() == VISIBLE_REGISTERS.len() => {
+                for (&register, value) in VISIBLE_REGISTERS.iter().zip(values) {
+                    self.cpu.registers_mut().write(register, value);
+                }
+                "OK".to_string()
+            },
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_register(&self, data: &str) -> String {
+        match usize::from_str_radix(data, 16).ok().and_then(|index| VISIBLE_REGISTERS.get(index)) {
+            Some(&register) => format!("{:04x}", self.cpu.registers().read(register)),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, data: &str) -> String {
+        let Some((index, value)) = data.split_once('=') else {
+            return "E01".to_string();
+        };
+        let register = usize::from_str_radix(index, 16).ok().and_then(|index| VISIBLE_REGISTERS.get(index));
+        match (register, parse_words(value).as_deref()) {
+            (Some(&register), Some(&[value])) => {
+                self.cpu.registers_mut().write(register, value);
+                "OK".to_string()
+            },
+            _ => "E01".to_string(),
+        }
+    }
+
+    /* replies with fewer bytes than asked for when the reply would not fit in a packet */
+    fn read_memory(&self, data: &str) -> String {
+        let Some((address, length)) = parse_pair(data, ',') else {
+            return "E01".to_string();
+        };
+        let length = (length as usize).min(MAX_PACKET / 2);
+        let mut reply: String = (0..length.div_ceil(2))
+            .map(|offset| format!("{:04x}", self.cpu.memory().peek((address as u16).wrapping_add(offset as u16))))
+            .collect();
+        /* an odd count ends on the high byte of the last word */
+        reply.truncate(length * 2);
+        reply
+    }
+
+    fn write_memory(&mut self, data: &str) -> String {
+        let Some((range, values)) = data.split_once(':') else {
+            return "E01".to_string();
+        };
+        /* whole words only; half a word can't be written without a read-modify-write GDB didn't ask for */
+        match (parse_pair(range, ','), parse_words(values)) {
+            (Some((address, length)), Some(values)) if values.len() * 2 == length as usize => {
+                for (offset, value) in values.into_iter().enumerate() {
+                    let address = (address as u16).wrapping_add(offset as u16);
+                    if self.cpu.memory_mut().write(address, value).is_err() {
+                        return "E01".to_string();
+                    }
+                }
+                "OK".to_string()
+            },
+            _ => "E01".to_string(),
+        }
+    }
+
+    /* only software breakpoints (type 0) are supported; anything else gets the empty reply */
+    fn set_breakpoint(&mut self, data: &str, insert: bool) -> String {
+        let mut fields = data.split(',');
+        let (Some("0"), Some(address)) = (fields.next(), fields.next()) else {
+            return String::new();
+        };
+        let Ok(address) = u16::from_str_radix(address, 16) else {
+            return "E01".to_string();
+        };
+        if insert {
+            self.breakpoints.insert(address);
+        } else {
+            self.breakpoints.remove(&address);
+        }
+        "OK".to_string()
+    }
+
+    fn resume(&mut self, single_step: bool) -> io::Result<String> {
+        let reply = self.run(single_step)?;
+        self.cpu.flush_tracers()?;
+        Ok(reply)
+    }
+
+    fn run(&mut self, single_step: bool) -> io::Result<String> {
+        if self.halted {
+            return Ok(self.stop_reply(SIGTRAP));
+        }
+        let mut executed: u32 = 0;
+        loop {
+            match self.cpu.step() {
+                Ok(StepOutcome::Halted) => {
+                    self.halted = true;
+                    return Ok(self.stop_reply(SIGTRAP));
+                },
+                Ok(_) => {},
+                Err(_) => return Ok(self.stop_reply(SIGILL)),
+            }
+            if single_step || self.breakpoints.contains(&self.cpu.registers().read(Register::PC)) {
+                return Ok(self.stop_reply(SIGTRAP));
+            }
+            executed += 1;
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) && self.interrupted()? {
+                return Ok(self.stop_reply(SIGINT));
+            }
+        }
+    }
+
+    /* checks, without blocking, whether the client sent Ctrl-C (0x03); other bytes are kept for read_packet */
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if let Some(index) = self.pending.iter().position(|&byte| byte == 0x03) {
+            self.pending.remove(index);
+            return Ok(true);
+        }
+        let ready = {
+            let mut fds = [PollFd::new(self.stream.as_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, PollTimeout::ZERO).map_err(io::Error::from)? > 0
+        };
+        if !ready {
+            return Ok(false);
+        }
+        let mut buffer = [0; 1];
+        match self.stream.read(&mut buffer)? {
+            0 => Ok(true),
+            _ if buffer[0] == 0x03 => Ok(true),
+            _ => {
+                self.pending.push_back(buffer[0]);
+                Ok(false)
+            },
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_pair(data: &str, separator: char) -> Option<(u32, u32)> {
+    let (first, second) = data.split_once(separator)?;
+    Some((u32::from_str_radix(first, 16).ok()?, u32::from_str_radix(second, 16).ok()?))
+}
+
+fn parse_words(data: &str) -> Option<Vec<u16>> {
+    if !data.len().is_multiple_of(4) || !data.is_ascii() {
+        return None;
+    }
+    (0..data.len())
+        .step_by(4)
+        .map(|index| u16::from_str_radix(&data[index..index + 4], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use crate::machine::tests::test_machine;
+
+    fn packet(data: &str) -> String {
+        format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+    }
+
+    /* sends each packet in turn and collects the raw replies, acking every one */
+    fn client(mut stream: UnixStream, packets: Vec<&'static str>) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let mut replies = Vec::new();
+            for data in packets {
+                stream.write_all(packet(data).as_bytes()).unwrap();
+                let mut ack = [0; 1];
+                stream.read_exact(&mut ack).unwrap();
+                assert_eq!(&ack, b"+");
+                if data == "k" {
+                    break;
+                }
+                let mut reply = Vec::new();
+                let mut byte = [0; 1];
+                while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+                    stream.read_exact(&mut byte).unwrap();
+                    reply.push(byte[0]);
+                }
+                stream.write_all(b"+").unwrap();
+                replies.push(String::from_utf8(reply).unwrap());
+            }
+            replies
+        })
+    }
+
+    fn cpu() -> CPU {
+        test_machine(".ORIG x3000\nADD R1, R1, #2\nADD R1, R1, #3\nHALT\n.END", b"", |machine| machine).0
+    }
+
+    #[test]
+    fn test_registers_memory_and_stepping() {
+        let (server, client_stream) = UnixStream::pair().unwrap();
+        let client = client(
+            client_stream,
+            vec!["qSupported:swbreak+", "?", "m3000,4", "s", "p1", "P2=00ff", "g", "M4000,2:abcd", "m4000,3", "k"],
+        );
+        let mut cpu = cpu();
+        GdbStub::new(&mut cpu, server).serve().unwrap();
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                packet("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+"),
+                packet("S05"),
+                packet("12621263"),
+                packet("S05"),
+                packet("0002"),
+                packet("OK"),
+                packet("0000000200ff0000000000000000000030010001"),
+                packet("OK"),
+                packet("abcd00"),
+            ]
+        );
+        assert_eq!(cpu.registers().read(Register::R2), 0x00FF);
+        assert_eq!(cpu.memory().peek(0x4000), 0xABCD);
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let (server, client_stream) = UnixStream::pair().unwrap();
+        let client = client(client_stream, vec!["Z0,3002,2", "c", "p1", "z0,3002,2", "c", "k"]);
+        let mut cpu = cpu();
+        GdbStub::new(&mut cpu, server).serve().unwrap();
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            vec![packet("OK"), packet("S05"), packet("0005"), packet("OK"), packet("W00")]
+        );
+    }
+
+    #[test]
+    fn test_memory_packet_limits() {
+        let (server, client_stream) = UnixStream::pair().unwrap();
+        let client = client(client_stream, vec!["m0,ffffffff", "M4000,1:ab", "k"]);
+        let mut cpu = cpu();
+        GdbStub::new(&mut cpu, server).serve().unwrap();
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0].len(), MAX_PACKET + 4);
+        assert_eq!(replies[1], packet("E01"));
+    }
+
+    #[test]
+    fn test_bytes_read_while_running_are_kept() {
+        let (server, mut client_stream) = UnixStream::pair().unwrap();
+        client_stream.write_all(packet("p1").as_bytes()).unwrap();
+        let mut cpu = cpu();
+        let mut stub = GdbStub::new(&mut cpu, server);
+        assert!(!stub.interrupted().unwrap());
+        assert_eq!(stub.read_packet().unwrap(), Some("p1".to_string()));
+    }
+
+    #[test]
+    fn test_only_plus_acks_a_reply() {
+        let (server, mut client_stream) = UnixStream::pair().unwrap();
+        client_stream.write_all(format!("{}\x03+", packet("p1")).as_bytes()).unwrap();
+        let mut cpu = cpu();
+        let mut stub = GdbStub::new(&mut cpu, server);
+        stub.send("OK").unwrap();
+        assert!(stub.interrupted().unwrap());
+        assert_eq!(stub.read_packet().unwrap(), Some("p1".to_string()));
+    }
+
+    #[test]
+    fn test_bad_checksum_is_nacked() {
+        let (server, mut client_stream) = UnixStream::pair().unwrap();
+        let handle = thread::spawn(move || {
+            client_stream.write_all(b"$g#00").unwrap();
+            let mut nack = [0; 1];
+            client_stream.read_exact(&mut nack).unwrap();
+            client_stream.write_all(packet("k").as_bytes()).unwrap();
+            let mut ack = [0; 1];
+            client_stream.read_exact(&mut ack).unwrap();
+            nack[0]
+        });
+        let mut cpu = cpu();
+        GdbStub::new(&mut cpu, server).serve().unwrap();
+        assert_eq!(handle.join().unwrap(), b'-');
+    }
+}
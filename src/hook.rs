@@ -0,0 +1,230 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use crate::cpu::CPU;
+use crate::register::Register;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HookAction {
+    Continue,
+    Stop, /* finish the current instruction, then return StepOutcome::Stopped */
+}
+
+/*
+ * Callbacks an embedder can attach to a CPU; every method defaults to doing nothing. Values passed
+ * as `&mut` may be replaced. before_instruction runs after the fetch, and stopping there leaves the
+ * instruction unexecuted with PC still pointing at it; the next step runs it, whatever the hook returns
+ * then, so a run can resume past the stop. The callbacks that get the CPU run with the hooks detached,
+ * so changes they make don't fire hooks themselves.
+ */
+pub trait Hook {
+    fn before_instruction(&mut self, _cpu: &mut CPU, _pc: u16, _instruction: &mut u16) -> HookAction {
+        HookAction::Continue
+    }
+
+    fn after_instruction(&mut self, _cpu: &mut CPU, _pc: u16, _instruction: u16) -> HookAction {
+        HookAction::Continue
+    }
+
+    /*
+     * runs when TRAP executes, before the service routine is entered or the native trap runs; the TRAP
+     * goes wherever the vector points afterwards
+     */
+    fn trap(&mut self, _cpu: &mut CPU, _trap_vector: &mut u16) -> HookAction {
+        HookAction::Continue
+    }
+
+    fn memory_read(&mut self, _address: u16, _value: &mut u16) -> HookAction {
+        HookAction::Continue
+    }
+
+    fn memory_write(&mut self, _address: u16, _value: &mut u16) -> HookAction {
+        HookAction::Continue
+    }
+
+    fn register_write(&mut self, _register: Register, _value: &mut u16) -> HookAction {
+        HookAction::Continue
+    }
+}
+
+/* the hooks attached to one machine, shared by its CPU, memory and registers */
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+    stop: bool,
+}
+
+pub type SharedHooks = Rc<RefCell<Hooks>>;
+
+impl Hooks {
+    pub fn add(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Box<dyn Hook>> {
+        (index < self.hooks.len()).then(|| self.hooks.remove(index))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /* whether a hook asked to stop since the last call */
+    pub fn take_stop(&mut self) -> bool {
+        mem::take(&mut self.stop)
+    }
+
+    pub fn request_stop(&mut self) {
+        self.stop = true;
+    }
+
+    pub fn memory_read(&mut self, address: u16, value: u16) -> u16 {
+        self.each(value, |hook, value| hook.memory_read(address, value))
+    }
+
+    pub fn memory_write(&mut self, address: u16, value: u16) -> u16 {
+        self.each(value, |hook, value| hook.memory_write(address, value))
+    }
+
+    pub fn register_write(&mut self, register: Register, value: u16) -> u16 {
+        self.each(value, |hook, value| hook.register_write(register, value))
+    }
+
+    fn each<F: FnMut(&mut dyn Hook, &mut u16) -> HookAction>(&mut self, mut value: u16, mut call: F) -> u16 {
+        for hook in &mut self.hooks {
+            if call(hook.as_mut(), &mut value) == HookAction::Stop {
+                self.stop = true;
+            }
+        }
+        value
+    }
+}
+
+/* calls `call` on each hook with the hook list taken out of `hooks`; true if any of them asked to stop */
+pub fn with_detached<F: FnMut(&mut dyn Hook) -> HookAction>(hooks: &SharedHooks, mut call: F) -> bool {
+    let mut detached = mem::take(&mut hooks.borrow_mut().hooks);
+    let mut stop = false;
+    for hook in &mut detached {
+        if call(hook.as_mut()) == HookAction::Stop {
+            stop = true;
+        }
+    }
+    /* keep hooks added while these were detached */
+    let mut hooks = hooks.borrow_mut();
+    detached.append(&mut hooks.hooks);
+    hooks.hooks = detached;
+    stop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StepOutcome;
+    use crate::machine::tests::test_machine;
+    use crate::state::Preset;
+
+    /* doubles every value loaded from x3010, hides R2 writes, turns TRAP x30 into OUT, logs traps and stops at `stop_at` */
+    struct Sandbox {
+        log: Rc<RefCell<Vec<String>>>,
+        stop_at: u16,
+    }
+
+    impl Hook for Sandbox {
+        fn before_instruction(&mut self, cpu: &mut CPU, pc: u16, _: &mut u16) -> HookAction {
+            if pc == self.stop_at {
+                let r1 = cpu.registers().read(Register::R1);
+                self.log.borrow_mut().push(format!("stop before x{:04X}, R1={}", pc, r1));
+                return HookAction::Stop;
+            }
+            HookAction::Continue
+        }
+
+        fn trap(&mut self, cpu: &mut CPU, trap_vector: &mut u16) -> HookAction {
+            self.log.borrow_mut().push(format!("trap x{:02X}", trap_vector));
+            if *trap_vector == 0x30 {
+                *trap_vector = 0x21;
+            }
+            cpu.registers_mut().write(Register::R0, b'!' as u16);
+            HookAction::Continue
+        }
+
+        fn memory_read(&mut self, address: u16, value: &mut u16) -> HookAction {
+            if address == 0x3010 {
+                *value *= 2;
+            }
+            HookAction::Continue
+        }
+
+        fn register_write(&mut self, register: Register, value: &mut u16) -> HookAction {
+            if register == Register::R2 {
+                *value = 0;
+                return HookAction::Stop;
+            }
+            HookAction::Continue
+        }
+    }
+
+    #[test]
+    fn test_hooks_observe_modify_and_stop() {
+        let source = ".ORIG x3000
+                 LD R1, DATA
+                 TRAP x30
+                 ADD R2, R1, #1
+                 ADD R3, R1, #1
+                 HALT
+                 .BLKW xB
+             DATA .FILL #21
+             .END";
+        let (mut cpu, console, _) = test_machine(source, b"", |machine| machine);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        cpu.add_hook(Box::new(Sandbox { log: log.clone(), stop_at: 0x3004 }));
+
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Stopped);
+        assert_eq!(cpu.registers().read(Register::R1), 42);
+        assert_eq!(cpu.registers().read(Register::R2), 0);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3003);
+        assert_eq!(console.borrow().output(), b"!");
+
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Stopped);
+        assert_eq!(cpu.registers().read(Register::R3), 43);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3004);
+        assert_eq!(*log.borrow(), ["trap x30", "stop before x3004, R1=42"]);
+
+        /* the hook asks to stop at x3004 again, but the run resumes past it */
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(log.borrow()[2..], ["stop before x3004, R1=42", "trap x25"]);
+
+        assert!(cpu.remove_hook(0).is_some());
+        assert!(cpu.remove_hook(0).is_none());
+        cpu.reset();
+        cpu.registers_mut().write(Register::PC, 0x3004);
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(log.borrow().len(), 4);
+    }
+
+    /* stops at the first memory write outside the keyboard, such as an interrupt pushing PSR and PC */
+    struct StopOnPush;
+
+    impl Hook for StopOnPush {
+        fn memory_write(&mut self, address: u16, _: &mut u16) -> HookAction {
+            if address == 0xFE00 { HookAction::Continue } else { HookAction::Stop }
+        }
+    }
+
+    #[test]
+    fn test_stop_during_interrupt_entry() {
+        let source = ".ORIG x3000\nLD R0, IE\nSTI R0, KBSR\nLOOP BR LOOP\nIE .FILL x4000\nKBSR .FILL xFE00\n.END";
+        let (mut cpu, _, _) = test_machine(source, b"k", |machine| {
+            machine
+                .preset(Preset::Memory(0x0180, 0x3002))
+                .preset(Preset::Register(Register::R6, 0x3000))
+                .hook(Box::new(StopOnPush))
+        });
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Stopped);
+        assert_eq!(cpu.registers().read(Register::PC), 0x3002);
+        /* the request was used up by the step that made it */
+        assert_eq!(cpu.step().unwrap(), StepOutcome::Continued);
+    }
+}
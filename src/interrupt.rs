@@ -0,0 +1,37 @@
+/* an interrupt request: the vector selects the table entry at x0100 + vector */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Interrupt {
+    pub vector: u16,
+    pub priority: u16,
+}
+
+pub const KEYBOARD_INTERRUPT: Interrupt = Interrupt { vector: 0x80, priority: 4 };
+
+impl Interrupt {
+    pub fn table_entry(self) -> u16 {
+        0x0100 + self.vector
+    }
+}
+
+/* the request the CPU takes: highest priority first, and only above the current priority level */
+pub fn select(requests: &[Interrupt], current_priority: u16) -> Option<Interrupt> {
+    requests
+        .iter()
+        .filter(|request| request.priority > current_priority)
+        .max_by_key(|request| request.priority)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let timer = Interrupt { vector: 0x81, priority: 6 };
+        assert_eq!(select(&[KEYBOARD_INTERRUPT, timer], 0), Some(timer));
+        assert_eq!(select(&[KEYBOARD_INTERRUPT, timer], 6), None);
+        assert_eq!(select(&[KEYBOARD_INTERRUPT], 3), Some(KEYBOARD_INTERRUPT));
+        assert_eq!(select(&[], 0), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::io;
+use std::ops::RangeInclusive;
+use crate::console::SharedConsole;
+use crate::device::Device;
+use crate::interrupt::{Interrupt, KEYBOARD_INTERRUPT};
+use crate::memory::MemoryMappedRegister;
+
+const KBSR_READY: u16 = 1 << 15;
+const KBSR_INTERRUPT_ENABLE: u16 = 1 << 14;
+
+/*
+ * A key stays latched in KBDR, with KBSR's ready bit set, until the program reads KBDR. Reading KBDR
+ * with nothing latched waits for the next key like GETC does, so closed input surfaces there rather
+ * than in KBSR, which just stays not ready.
+ */
+pub struct Keyboard {
+    console: SharedConsole,
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    pub fn new(console: SharedConsole) -> Self {
+        Keyboard { console, status: 0, data: 0 }
+    }
+
+    fn latch(&mut self, byte: u8) {
+        self.data = byte as u16;
+        self.status |= KBSR_READY;
+    }
+
+    /* the latched key if there is one, otherwise blocks for the next one */
+    pub fn read_key(&mut self) -> io::Result<u8> {
+        if self.status & KBSR_READY != 0 {
+            self.status &= !KBSR_READY;
+            return Ok(self.data as u8);
+        }
+        self.console.borrow_mut().read_byte()
+    }
+
+    fn poll(&mut self) -> io::Result<()> {
+        if self.status & KBSR_READY == 0 && self.console.borrow_mut().poll()? {
+            let byte = self.console.borrow_mut().read_byte()?;
+            self.latch(byte);
+        }
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn range(&self) -> RangeInclusive<u16> {
+        MemoryMappedRegister::KBSR as u16..=MemoryMappedRegister::KBDR as u16 + 1
+    }
+
+    fn read(&mut self, address: u16) -> io::Result<u16> {
+        if address == MemoryMappedRegister::KBSR as u16 {
+            self.poll()?;
+        } else if address == MemoryMappedRegister::KBDR as u16 {
+            self.data = self.read_key()? as u16;
+            return Ok(self.data);
+        }
+        Ok(self.peek(address))
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            address if address == MemoryMappedRegister::KBSR as u16 => self.status,
+            address if address == MemoryMappedRegister::KBDR as u16 => self.data,
+            _ => 0,
+        }
+    }
+
+    /* only the interrupt-enable bit of KBSR is writable */
+    fn write(&mut self, address: u16, value: u16) -> io::Result<()> {
+        if address == MemoryMappedRegister::KBSR as u16 {
+            self.status = (self.status & KBSR_READY) | (value & KBSR_INTERRUPT_ENABLE);
+        }
+        Ok(())
+    }
+
+    fn interrupt(&mut self) -> io::Result<Option<Interrupt>> {
+        if self.status & KBSR_INTERRUPT_ENABLE == 0 {
+            return Ok(None);
+        }
+        self.poll()?;
+        Ok((self.status & KBSR_READY != 0).then_some(KEYBOARD_INTERRUPT))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn test_keyboard_latch() {
+        let mut keyboard = Keyboard::new(Rc::new(RefCell::new(BufferConsole::new(b"abc"))));
+        keyboard.write(0xFE00, 0xFFFF).unwrap();
+        assert_eq!(keyboard.peek(0xFE00), KBSR_INTERRUPT_ENABLE);
+        assert_eq!(keyboard.interrupt().unwrap(), Some(KEYBOARD_INTERRUPT));
+        assert_eq!(keyboard.read(0xFE00).unwrap(), KBSR_READY | KBSR_INTERRUPT_ENABLE);
+        assert_eq!(keyboard.read(0xFE02).unwrap(), b'a' as u16);
+        assert_eq!(keyboard.peek(0xFE00), KBSR_INTERRUPT_ENABLE);
+        assert_eq!(keyboard.read(0xFE00).unwrap() & KBSR_READY, KBSR_READY);
+        assert_eq!(keyboard.read_key().unwrap(), b'b');
+        assert_eq!(keyboard.read(0xFE02).unwrap(), b'c' as u16);
+        assert_eq!(keyboard.read(0xFE00).unwrap(), KBSR_INTERRUPT_ENABLE);
+        assert_eq!(keyboard.interrupt().unwrap(), None);
+        assert_eq!(keyboard.read(0xFE02).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(keyboard.read_key().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
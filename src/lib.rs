@@ -0,0 +1,41 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod memory;
+pub mod register;
+pub mod opcode;
+pub mod flag;
+mod utils;
+pub mod console;
+pub mod trap;
+pub mod cpu;
+pub mod loader;
+pub mod assembler;
+pub mod symbols;
+pub mod terminal;
+pub mod disassembler;
+pub mod debugger;
+pub mod device;
+pub mod display;
+pub mod exception;
+pub mod gdb;
+pub mod hook;
+pub mod interrupt;
+pub mod keyboard;
+pub mod os;
+pub mod batch;
+pub mod machine;
+pub mod state;
+pub mod trace;
+pub mod watch;
+
+pub use cpu::CPU;
+pub use machine::MachineBuilder;
+pub use memory::Memory;
+pub use opcode::Opcode;
+pub use register::Registers;
+pub use trap::TrapCode;
+
+/* compiles the README's code examples as doctests */
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+struct ReadmeDoctests;
@@ -0,0 +1,237 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::memory::Memory;
+
+const MEMORY_WORDS: usize = 1 << 16;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Image {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Image {
+    pub fn new(origin: u16, words: Vec<u16>) -> Self {
+        Image { origin, words }
+    }
+
+    /* parses an LC-3 .obj file: a big-endian origin word followed by big-endian words */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        if bytes.len() < 2 {
+            return Err(ImageError::Truncated(bytes.len()));
+        }
+        if !bytes.len().is_multiple_of(2) {
+            return Err(ImageError::OddLength(bytes.len()));
+        }
+        let mut words = bytes
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = words.next().unwrap();
+        let words: Vec<u16> = words.collect();
+        if origin as usize + words.len() > MEMORY_WORDS {
+            return Err(ImageError::OutOfBounds { origin, length: words.len() });
+        }
+        Ok(Image::new(origin, words))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((self.words.len() + 1) * 2);
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes
+    }
+
+    /* exclusive end address, as usize so an image ending at xFFFF doesn't wrap */
+    pub fn end(&self) -> usize {
+        self.origin as usize + self.words.len()
+    }
+
+    pub fn overlaps(&self, other: &Image) -> bool {
+        (self.origin as usize) < other.end() && (other.origin as usize) < self.end()
+    }
+}
+
+/* images `image` and `other` (indices into the list, `other` the earlier one) share start..=end */
+#[derive(Debug, PartialEq)]
+pub struct Overlap {
+    pub image: usize,
+    pub other: usize,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "image {} overlaps image {} at x{:04X}-x{:04X}",
+            self.image, self.other, self.start, self.end
+        )
+    }
+}
+
+impl error::Error for Overlap {}
+
+/* the first image that overlaps an earlier one, if any */
+pub fn find_overlap(images: &[Image]) -> Option<Overlap> {
+    images.iter().enumerate().find_map(|(index, image)| {
+        let (other, earlier) = images[..index].iter().enumerate().find(|(_, earlier)| image.overlaps(earlier))?;
+        Some(Overlap {
+            image: index,
+            other,
+            start: image.origin.max(earlier.origin),
+            end: (image.end().min(earlier.end()) - 1) as u16,
+        })
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    Truncated(usize),
+    OddLength(usize),
+    OutOfBounds { origin: u16, length: usize },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Truncated(length) => {
+                write!(f, "truncated image ({} bytes, need at least an origin word)", length)
+            },
+            ImageError::OddLength(length) => {
+                write!(f, "image has an odd number of bytes ({})", length)
+            },
+            ImageError::OutOfBounds { origin, length } => {
+                write!(f, "image of {} words at x{:04X} runs past the end of memory", length, origin)
+            },
+        }
+    }
+}
+
+impl error::Error for ImageError {}
+
+#[derive(Debug)]
+pub enum LoaderError {
+    Io(PathBuf, io::Error),
+    Image(PathBuf, ImageError),
+    Overlap { path: PathBuf, other: PathBuf, start: u16, end: u16 },
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderError::Io(path, error) => write!(f, "{}: {}", path.display(), error),
+            LoaderError::Image(path, error) => write!(f, "{}: {}", path.display(), error),
+            LoaderError::Overlap { path, other, start, end } => write!(
+                f,
+                "{}: overlaps {} at x{:04X}-x{:04X}",
+                path.display(),
+                other.display(),
+                start,
+                end
+            ),
+        }
+    }
+}
+
+impl error::Error for LoaderError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoaderError::Io(_, error) => Some(error),
+            LoaderError::Image(_, error) => Some(error),
+            LoaderError::Overlap { .. } => None,
+        }
+    }
+}
+
+pub fn read_image(path: &Path) -> Result<Image, LoaderError> {
+    let bytes = fs::read(path).map_err(|error| LoaderError::Io(path.to_path_buf(), error))?;
+    Image::from_bytes(&bytes).map_err(|error| LoaderError::Image(path.to_path_buf(), error))
+}
+
+/* reads every image and checks that no two of them overlap */
+pub fn read_images(paths: &[PathBuf]) -> Result<Vec<Image>, LoaderError> {
+    let images = paths.iter().map(|path| read_image(path)).collect::<Result<Vec<Image>, LoaderError>>()?;
+    if let Some(Overlap { image, other, start, end }) = find_overlap(&images) {
+        return Err(LoaderError::Overlap {
+            path: paths[image].clone(),
+            other: paths[other].clone(),
+            start,
+            end,
+        });
+    }
+    Ok(images)
+}
+
+/* reads every image first so nothing is copied into memory unless they all fit together */
+pub fn load_images(paths: &[PathBuf], memory: &mut Memory) -> Result<Vec<Image>, LoaderError> {
+    let images = read_images(paths)?;
+    for (path, image) in paths.iter().zip(&images) {
+        memory.load(image.origin, &image.words).map_err(|_| {
+            let bounds = ImageError::OutOfBounds { origin: image.origin, length: image.words.len() };
+            LoaderError::Image(path.clone(), bounds)
+        })?;
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn write_temp(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("lc3-loader-{}-{}", process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_image_from_bytes_valid() {
+        let image = Image::from_bytes(&[0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]).unwrap();
+        assert_eq!(image, Image::new(0x3000, vec![0x1234, 0xF025]));
+        assert_eq!(image.to_bytes(), vec![0x30, 0x00, 0x12, 0x34, 0xF0, 0x25]);
+    }
+
+    #[test]
+    fn test_image_from_bytes_invalid() {
+        assert_eq!(Image::from_bytes(&[0x30]), Err(ImageError::Truncated(1)));
+        assert_eq!(Image::from_bytes(&[0x30, 0x00, 0x12]), Err(ImageError::OddLength(3)));
+        assert_eq!(
+            Image::from_bytes(&[0xFF, 0xFF, 0x00, 0x01, 0x00, 0x02]),
+            Err(ImageError::OutOfBounds { origin: 0xFFFF, length: 2 })
+        );
+    }
+
+    #[test]
+    fn test_load_images() {
+        let first = write_temp("first.obj", &[0x30, 0x00, 0x00, 0x01, 0x00, 0x02]);
+        let second = write_temp("second.obj", &[0x40, 0x00, 0x00, 0x03]);
+        let mut memory = Memory::new();
+        let images = load_images(&[first, second], &mut memory).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(memory.peek(0x3000), 0x0001);
+        assert_eq!(memory.peek(0x3001), 0x0002);
+        assert_eq!(memory.peek(0x4000), 0x0003);
+    }
+
+    #[test]
+    fn test_load_images_overlap() {
+        let first = write_temp("overlap-a.obj", &[0x30, 0x00, 0x00, 0x01, 0x00, 0x02]);
+        let second = write_temp("overlap-b.obj", &[0x30, 0x01, 0x00, 0x03]);
+        let mut memory = Memory::new();
+        match load_images(&[first, second], &mut memory) {
+            Err(LoaderError::Overlap { start, end, .. }) => {
+                assert_eq!((start, end), (0x3001, 0x3001));
+            },
+            other => panic!("expected overlap, got {:?}", other),
+        }
+        assert_eq!(memory.peek(0x3000), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use std::error;
+use std::fmt;
+use crate::console::{std_console, SharedConsole};
+use crate::cpu::{Limits, CPU};
+use crate::device::{Device, DeviceError};
+use crate::hook::Hook;
+use crate::loader::{find_overlap, Image, Overlap};
+use crate::memory::MemoryError;
+use crate::os;
+use crate::register::Register;
+use crate::state::Preset;
+use crate::trace::Tracer;
+
+/*
+ * Configures a machine and hands back a CPU that is ready to step: at the OS boot code with `os`,
+ * otherwise at the entry point, which defaults to the first image's origin (x3000 without images).
+ * Presets are applied last, so they win over both; with `os`, register presets are the values the
+ * program starts with once boot hands over. Without a console it uses stdin and stdout.
+ */
+#[derive(Default)]
+pub struct MachineBuilder {
+    images: Vec<Image>,
+    devices: Vec<Box<dyn Device>>,
+    console: Option<SharedConsole>,
+    display_latency: u32,
+    os: bool,
+    entry: Option<u16>,
+    limits: Limits,
+    presets: Vec<Preset>,
+    tracers: Vec<Tracer>,
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MachineError {
+    ImageOverlap(Overlap),
+    Device(DeviceError),
+    Memory(MemoryError),
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::ImageOverlap(overlap) => write!(f, "{}", overlap),
+            MachineError::Device(error) => write!(f, "{}", error),
+            MachineError::Memory(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for MachineError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MachineError::ImageOverlap(overlap) => Some(overlap),
+            MachineError::Device(error) => Some(error),
+            MachineError::Memory(error) => Some(error),
+        }
+    }
+}
+
+impl From<DeviceError> for MachineError {
+    fn from(error: DeviceError) -> Self {
+        MachineError::Device(error)
+    }
+}
+
+impl From<MemoryError> for MachineError {
+    fn from(error: MemoryError) -> Self {
+        MachineError::Memory(error)
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        MachineBuilder::default()
+    }
+
+    /* images may not overlap each other, but may overwrite the OS, e.g. to replace vector table entries */
+    pub fn image(mut self, image: Image) -> Self {
+        self.images.push(image);
+        self
+    }
+
+    pub fn device(mut self, device: Box<dyn Device>) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    pub fn console(mut self, console: SharedConsole) -> Self {
+        self.console = Some(console);
+        self
+    }
+
+    pub fn display_latency(mut self, steps: u32) -> Self {
+        self.display_latency = steps;
+        self
+    }
+
+    /* loads the bundled OS and routes TRAP through the vector table */
+    pub fn os(mut self, enabled: bool) -> Self {
+        self.os = enabled;
+        self
+    }
+
+    /* where the user program starts; with the OS, where boot hands over to it */
+    pub fn entry(mut self, address: u16) -> Self {
+        self.entry = Some(address);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn preset(mut self, preset: Preset) -> Self {
+        self.presets.push(preset);
+        self
+    }
+
+    pub fn tracer(mut self, tracer: Tracer) -> Self {
+        self.tracers.push(tracer);
+        self
+    }
+
+    pub fn hook(mut self, hook: Box<dyn Hook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    pub fn build(self) -> Result<CPU, MachineError> {
+        if let Some(overlap) = find_overlap(&self.images) {
+            return Err(MachineError::ImageOverlap(overlap));
+        }
+        let entry = self.entry.or(self.images.first().map(|image| image.origin)).unwrap_or(0x3000);
+        let console = self.console.unwrap_or_else(std_console);
+        let mut cpu = CPU::with_console(console, self.display_latency);
+        for device in self.devices {
+            cpu.memory_mut().attach(device)?;
+        }
+        if self.os {
+            os::install(&mut cpu);
+            os::set_user_entry(&mut cpu, entry);
+        }
+        for image in &self.images {
+            cpu.memory_mut().load(image.origin, &image.words)?;
+        }
+        cpu.set_limits(self.limits);
+        for tracer in self.tracers {
+            cpu.add_tracer(tracer);
+        }
+        if self.os {
+            os::boot(&mut cpu);
+        } else {
+            cpu.reset();
+            cpu.registers_mut().write(Register::PC, entry);
+        }
+        for preset in self.presets {
+            match preset {
+                /* with the OS, for the program that boot hands over to */
+                Preset::Register(register, value) if self.os => os::set_user_register(&mut cpu, register, value),
+                Preset::Register(register, value) => cpu.registers_mut().write(register, value),
+                Preset::Memory(address, value) => cpu.memory_mut().poke(address, value),
+            }
+        }
+        /* attached last so loading, booting and presets don't reach them */
+        for hook in self.hooks {
+            cpu.add_hook(hook);
+        }
+        Ok(cpu)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::ops::RangeInclusive;
+    use std::rc::Rc;
+    use crate::assembler::assemble;
+    use crate::console::BufferConsole;
+    use crate::cpu::StepOutcome;
+    use crate::symbols::SymbolTable;
+
+    /*
+     * The machine most tests start from: `source` assembled and loaded, `input` waiting at the keyboard,
+     * and the console kept so the test can read the output back. `configure` adds anything else.
+     */
+    pub(crate) fn test_machine<F: FnOnce(MachineBuilder) -> MachineBuilder>(
+        source: &str,
+        input: &[u8],
+        configure: F,
+    ) -> (CPU, Rc<RefCell<BufferConsole>>, SymbolTable) {
+        let program = assemble(source).unwrap();
+        let console = Rc::new(RefCell::new(BufferConsole::new(input)));
+        let machine = MachineBuilder::new().image(program.image).console(console.clone());
+        (configure(machine).build().unwrap(), console, program.symbols)
+    }
+
+    /* mapped into user space so the program can read it with or without the OS */
+    struct Constant;
+
+    impl Device for Constant {
+        fn range(&self) -> RangeInclusive<u16> {
+            0x5000..=0x5000
+        }
+
+        fn read(&mut self, address: u16) -> io::Result<u16> {
+            Ok(self.peek(address))
+        }
+
+        fn peek(&self, _: u16) -> u16 {
+            42
+        }
+
+        fn write(&mut self, _: u16, _: u16) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let source = ".ORIG x4000\nLDI R0, PORT\nADD R2, R0, #0\nOUT\nHALT\nPORT .FILL x5000\n.END";
+        for os in [false, true] {
+            let (mut cpu, console, _) = test_machine(source, b"", |machine| machine.device(Box::new(Constant)).os(os));
+            assert_eq!(cpu.run_until(|_| false).unwrap(), StepOutcome::Halted);
+            assert_eq!(cpu.registers().read(Register::R2), 42);
+            assert_eq!(console.borrow().output(), b"*HALT");
+        }
+
+        let (cpu, _, _) = test_machine(source, b"", |machine| {
+            machine
+                .entry(0x4001)
+                .preset(Preset::Register(Register::R0, 7))
+                .preset(Preset::Memory(0x4004, 0x1234))
+        });
+        assert_eq!(cpu.registers().read(Register::PC), 0x4001);
+        assert_eq!(cpu.registers().read(Register::R0), 7);
+        assert_eq!(cpu.memory().peek(0x4004), 0x1234);
+    }
+
+    #[test]
+    fn test_builder_presets_under_the_os() {
+        /* boot sets R0, R6, COND and PC itself, but the presets still reach the program */
+        let copy = ".ORIG x3000\nBRn COPY\nHALT\nCOPY ADD R1, R0, #0\nADD R2, R6, #0\nHALT\n.END";
+        let (mut cpu, _, _) = test_machine(copy, b"", |machine| {
+            machine
+                .os(true)
+                .preset(Preset::Register(Register::R0, 7))
+                .preset(Preset::Register(Register::R6, 0x5000))
+                .preset(Preset::Register(Register::R3, 9))
+                .preset(Preset::Register(Register::COND, 4))
+        });
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::R1), 7);
+        assert_eq!(cpu.registers().read(Register::R2), 0x5000);
+        assert_eq!(cpu.registers().read(Register::R3), 9);
+    }
+
+    #[test]
+    fn test_builder_rejects_overlapping_images() {
+        let program = Image::new(0x4000, vec![0; 5]);
+        let overlapping = Image::new(0x4003, vec![0]);
+        let result = MachineBuilder::new().image(program).image(overlapping).build();
+        let overlap = Overlap { image: 1, other: 0, start: 0x4003, end: 0x4003 };
+        assert_eq!(result.err(), Some(MachineError::ImageOverlap(overlap)));
+    }
+}
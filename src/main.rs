@@ -1,15 +1,311 @@
-use crate::cpu::CPU;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::Duration;
+use corroded_lc3_vm::assembler::{assemble, parse_number};
+use corroded_lc3_vm::batch;
+use corroded_lc3_vm::console::{std_console, BufferConsole, ConsoleReader};
+use corroded_lc3_vm::cpu::{Limits, StepOutcome};
+use corroded_lc3_vm::debugger::Debugger;
+use corroded_lc3_vm::disassembler::disassemble_memory;
+use corroded_lc3_vm::gdb::GdbStub;
+use corroded_lc3_vm::loader::{load_images, read_images};
+use corroded_lc3_vm::state::{parse_state, parse_value};
+use corroded_lc3_vm::symbols::{parse_sym_file, to_sym_file, SymbolTable};
+use corroded_lc3_vm::terminal::enable_raw_mode;
+use corroded_lc3_vm::trace::{TraceFormat, Tracer};
+use corroded_lc3_vm::{MachineBuilder, Memory};
 
-mod memory;
-mod register;
-mod opcode;
-mod flag;
-mod utils;
-mod trap;
-mod cpu;
+const USAGE: &str = "usage:
+  corroded-lc3-vm <image.obj>... [<machine>] [--display-latency <steps>] [<limits>]
+  corroded-lc3-vm batch <image.obj>... [<machine>] [--input <text> | --input-file <file>] [--output <file>] [<limits>]
+  corroded-lc3-vm asm <source.asm> [-o <image.obj>]
+  corroded-lc3-vm disasm <image.obj>... [--symbols <file.sym>] [--start <addr>] [--end <addr>]
+  corroded-lc3-vm debug <image.obj>... [<machine>]
+  corroded-lc3-vm gdb <image.obj>... [<machine>] --listen <port|socket-path>
+machine: [--os] [--symbols <file.sym>] [--entry <addr|label>] [--set <target=value,...>] [--state <file>]
+         [--trace <file>] [--trace-json <file>]
+limits: [--max-instructions <count>] [--timeout <seconds>] [--max-output <chars>]";
 
+const MACHINE_OPTIONS: [&str; 6] = ["--symbols", "--entry", "--set", "--state", "--trace", "--trace-json"];
+const LIMIT_OPTIONS: [&str; 3] = ["--max-instructions", "--timeout", "--max-output"];
 
 fn main() {
-    let mut cpu = CPU::new();
-    cpu.run()
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        None => Err(USAGE.to_string()),
+        Some("batch") => run_batch(&args[1..]),
+        Some("asm") => assemble_file(&args[1..]),
+        Some("disasm") => disassemble_files(&args[1..]),
+        Some("debug") => debug_images(&args[1..]),
+        Some("gdb") => serve_gdb(&args[1..]),
+        Some(_) => run_images(&args),
+    };
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1)
+    }
+}
+
+fn run_images(args: &[String]) -> Result<(), String> {
+    let (paths, options) = split_options(args, &[&["--display-latency"][..], &MACHINE_OPTIONS, &LIMIT_OPTIONS].concat(), &["--os"])?;
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let latency = match options.get("--display-latency") {
+        Some(text) => text.parse().map_err(|_| format!("error: invalid latency `{}`", text))?,
+        None => 0,
+    };
+    let mut cpu = machine(&paths, &options)?
+        .console(std_console())
+        .display_latency(latency)
+        .limits(parse_limits(&options)?)
+        .build()
+        .map_err(|error| format!("error: {}", error))?;
+    /* dropped on every way out of this function, putting the terminal back before errors are printed */
+    let _raw_mode = enable_raw_mode().map_err(|error| format!("error: terminal: {}", error))?;
+    match cpu.run_until(|_| false).map_err(|error| format!("\nerror: {}", error))? {
+        StepOutcome::LimitReached(limit) => Err(format!("\nstopped: {}", limit)),
+        _ => Ok(()),
+    }
+}
+
+/* no terminal: input comes from the options, and a JSON summary including the output goes to stdout */
+fn run_batch(args: &[String]) -> Result<(), String> {
+    let names = [&["--input", "--input-file", "--output"][..], &MACHINE_OPTIONS, &LIMIT_OPTIONS].concat();
+    let (paths, options) = split_options(args, &names, &["--os"])?;
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let input = match (options.get("--input"), options.get("--input-file")) {
+        (Some(_), Some(_)) => return Err(format!("error: give either --input or --input-file\n{}", USAGE)),
+        (Some(text), None) => text.clone().into_bytes(),
+        (None, Some(path)) => fs::read(path).map_err(|error| format!("error: {}: {}", path, error))?,
+        (None, None) => Vec::new(),
+    };
+    let console = Rc::new(RefCell::new(BufferConsole::new(&input)));
+    let mut cpu = machine(&paths, &options)?
+        .console(console.clone())
+        .limits(parse_limits(&options)?)
+        .build()
+        .map_err(|error| format!("error: {}", error))?;
+    let summary = batch::run(&mut cpu);
+    let console = console.borrow();
+    if let Some(path) = options.get("--output") {
+        fs::write(path, console.output()).map_err(|error| format!("error: {}: {}", path, error))?;
+    }
+    println!("{}", summary.to_json(console.output()));
+    Ok(())
+}
+
+fn assemble_file(args: &[String]) -> Result<(), String> {
+    let (source_path, output_path) = match args {
+        [source] => (PathBuf::from(source), PathBuf::from(source).with_extension("obj")),
+        [source, flag, output] if flag == "-o" => (PathBuf::from(source), PathBuf::from(output)),
+        _ => return Err(USAGE.to_string()),
+    };
+    let source = fs::read_to_string(&source_path)
+        .map_err(|error| format!("error: {}: {}", source_path.display(), error))?;
+    let path = source_path.display().to_string();
+    let program = assemble(&source).map_err(|errors| {
+        let mut report: String = errors.iter().map(|error| error.render(&path, &source)).collect();
+        report.push_str(&format!("{} error(s) assembling {}", errors.len(), path));
+        report
+    })?;
+    fs::write(&output_path, program.image.to_bytes())
+        .map_err(|error| format!("error: {}: {}", output_path.display(), error))?;
+    let symbols_path = output_path.with_extension("sym");
+    fs::write(&symbols_path, to_sym_file(&program.symbols))
+        .map_err(|error| format!("error: {}: {}", symbols_path.display(), error))
+}
+
+fn disassemble_files(args: &[String]) -> Result<(), String> {
+    let (paths, options) = split_options(args, &["--symbols", "--start", "--end"], &[])?;
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let mut memory = Memory::new();
+    let images = load_images(&paths, &mut memory).map_err(|error| format!("error: {}", error))?;
+    let symbols = Some(load_symbols(&paths, options.get("--symbols"))?);
+    let ranges: Vec<(u16, u16)> = match (options.get("--start"), options.get("--end")) {
+        (None, None) => images
+            .iter()
+            .filter(|image| !image.words.is_empty())
+            .map(|image| (image.origin, (image.end() - 1) as u16))
+            .collect(),
+        (start, end) => {
+            let start = start.map(|text| parse_address(text)).transpose()?.unwrap_or(0);
+            let end = end.map(|text| parse_address(text)).transpose()?.unwrap_or(0xFFFF);
+            vec![(start, end)]
+        },
+    };
+    for (start, end) in ranges {
+        for line in disassemble_memory(&memory, start, end, symbols.as_ref()) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+fn debug_images(args: &[String]) -> Result<(), String> {
+    let (paths, options) = split_options(args, &MACHINE_OPTIONS, &["--os"])?;
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    /* commands and the program's keys come from the same stdin queue, in the order they were typed */
+    let console = std_console();
+    let cpu = machine(&paths, &options)?
+        .console(console.clone())
+        .build()
+        .map_err(|error| format!("error: {}", error))?;
+    let symbols = load_symbols(&paths, options.get("--symbols"))?;
+    let mut debugger = Debugger::new(cpu, symbols);
+    debugger
+        .repl(ConsoleReader::new(console), &mut io::stdout())
+        .map_err(|error| format!("error: {}", error))
+}
+
+/* a bare port number listens on 127.0.0.1, anything else is a Unix socket path; serves one client */
+fn serve_gdb(args: &[String]) -> Result<(), String> {
+    let (paths, options) = split_options(args, &[&["--listen"][..], &MACHINE_OPTIONS].concat(), &["--os"])?;
+    let Some(address) = options.get("--listen") else {
+        return Err(USAGE.to_string());
+    };
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+    let mut cpu = machine(&paths, &options)?
+        .build()
+        .map_err(|error| format!("error: {}", error))?;
+    let error = |error: io::Error| format!("error: {}: {}", address, error);
+    if let Ok(port) = address.parse::<u16>() {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(error)?;
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept().map_err(error)?;
+        GdbStub::new(&mut cpu, stream).serve().map_err(error)
+    } else {
+        let listener = UnixListener::bind(address).map_err(error)?;
+        eprintln!("waiting for gdb on {}", address);
+        let (stream, _) = listener.accept().map_err(error)?;
+        let result = GdbStub::new(&mut cpu, stream).serve().map_err(error);
+        let _ = fs::remove_file(address);
+        result
+    }
+}
+
+/* the images at `paths`, checked against each other, configured by the MACHINE_OPTIONS and `--os` */
+fn machine(paths: &[PathBuf], options: &HashMap<String, String>) -> Result<MachineBuilder, String> {
+    let images = read_images(paths).map_err(|error| format!("error: {}", error))?;
+    let symbols = load_symbols(paths, options.get("--symbols"))?;
+    let mut builder = images
+        .into_iter()
+        .fold(MachineBuilder::new().os(options.contains_key("--os")), MachineBuilder::image);
+    if let Some(text) = options.get("--entry") {
+        let entry = parse_value(text, &symbols).ok_or_else(|| format!("error: invalid entry point `{}`", text))?;
+        builder = builder.entry(entry);
+    }
+    /* the file first, so --set can override single values from it */
+    let mut presets = Vec::new();
+    if let Some(path) = options.get("--state") {
+        let text = fs::read_to_string(path).map_err(|error| format!("error: {}: {}", path, error))?;
+        presets.extend(parse_state(&text, &symbols).map_err(|error| format!("error: {}: {}", path, error))?);
+    }
+    if let Some(text) = options.get("--set") {
+        presets.extend(parse_state(text, &symbols).map_err(|error| format!("error: --set: {}", error))?);
+    }
+    builder = presets.into_iter().fold(builder, MachineBuilder::preset);
+    for (name, format) in [("--trace", TraceFormat::Text), ("--trace-json", TraceFormat::JsonLines)] {
+        if let Some(path) = options.get(name) {
+            let file = File::create(path).map_err(|error| format!("error: {}: {}", path, error))?;
+            builder = builder.tracer(Tracer::new(Box::new(BufWriter::new(file)), format, Some(symbols.clone())));
+        }
+    }
+    Ok(builder)
+}
+
+/* an explicit symbol file, or the .sym files `asm` writes next to each image */
+fn load_symbols(paths: &[PathBuf], explicit: Option<&String>) -> Result<SymbolTable, String> {
+    if let Some(path) = explicit {
+        return read_symbols(Path::new(path));
+    }
+    let mut symbols = SymbolTable::new();
+    for path in paths {
+        let symbols_path = path.with_extension("sym");
+        if symbols_path.exists() {
+            symbols.extend(read_symbols(&symbols_path)?);
+        }
+    }
+    Ok(symbols)
+}
+
+fn read_symbols(path: &Path) -> Result<SymbolTable, String> {
+    let text = fs::read_to_string(path).map_err(|error| format!("error: {}: {}", path.display(), error))?;
+    parse_sym_file(&text).map_err(|error| format!("error: {}: {}", path.display(), error))
+}
+
+fn parse_limits(options: &HashMap<String, String>) -> Result<Limits, String> {
+    let count = |name: &str| {
+        options
+            .get(name)
+            .map(|text| text.parse::<u64>().map_err(|_| format!("error: invalid {} `{}`", name, text)))
+            .transpose()
+    };
+    let timeout = options
+        .get("--timeout")
+        .map(|text| match text.parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(Duration::from_secs_f64(seconds)),
+            _ => Err(format!("error: invalid --timeout `{}`", text)),
+        })
+        .transpose()?;
+    Ok(Limits {
+        max_instructions: count("--max-instructions")?,
+        timeout,
+        max_output: count("--max-output")?,
+    })
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    match parse_number(text) {
+        Some(value) if (0..=0xFFFF).contains(&value) => Ok(value as u16),
+        _ => Err(format!("error: invalid address `{}`", text)),
+    }
+}
+
+/* separates `--name value` options and bare `--flag`s (stored with an empty value) from positional arguments */
+fn split_options(
+    args: &[String],
+    names: &[&str],
+    flags: &[&str],
+) -> Result<(Vec<String>, HashMap<String, String>), String> {
+    let mut positional = Vec::new();
+    let mut options = HashMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if flags.contains(&arg.as_str()) {
+            options.insert(arg.clone(), String::new());
+        } else if arg.starts_with("--") {
+            if !names.contains(&arg.as_str()) {
+                return Err(format!("error: unknown option `{}`\n{}", arg, USAGE));
+            }
+            let value = args.next().ok_or_else(|| format!("error: `{}` needs a value", arg))?;
+            options.insert(arg.clone(), value.clone());
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    Ok((positional, options))
 }
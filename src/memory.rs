@@ -1,36 +1,266 @@
-use crate::utils::{check_key, get_char_byte};
+use std::error;
+use std::fmt;
+use std::io;
+use crate::console::{std_console, SharedConsole};
+use crate::device::{Device, DeviceError};
+use crate::hook::SharedHooks;
+use crate::interrupt::Interrupt;
+use crate::keyboard::Keyboard;
+use crate::trace::{AccessKind, MemoryAccess};
+use crate::watch::SharedWatches;
 
 const MEMORY_MAX: usize = 65536;
 
-enum MemoryMappedRegister {
+pub enum MemoryMappedRegister {
     KBSR = 0xFE00, /* keyboard status */
-    KBDR = 0xFE02  /* keyboard data */
+    KBDR = 0xFE02, /* keyboard data */
+    DSR = 0xFE04,  /* display status */
+    DDR = 0xFE06,  /* display data */
+    MCR = 0xFFFE   /* machine control, bit 15 enables the clock */
 }
 
+pub const MCR_CLOCK_ENABLE: u16 = 1 << 15;
+
+#[derive(Debug, PartialEq)]
+pub enum MemoryError {
+    OutOfBounds { origin: u16, length: usize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { origin, length } => {
+                write!(f, "{} words at x{:04X} run past the end of memory", length, origin)
+            },
+        }
+    }
+}
+
+impl error::Error for MemoryError {}
+
+/* RAM plus a bus: accesses inside a device's range go to the device, everything else to RAM */
 pub struct Memory {
     data: [u16; MEMORY_MAX],
+    keyboard: Keyboard,
+    devices: Vec<Box<dyn Device>>,
+    recording: Option<Vec<MemoryAccess>>, /* reads and writes since start_recording, for tracing */
+    hooks: SharedHooks,
+    watches: SharedWatches,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
 }
 
 impl Memory {
+    /* memory whose keyboard reads stdin; fine for tools that never execute anything */
     pub fn new() -> Self {
+        Memory::with_console(std_console())
+    }
+
+    pub fn with_console(console: SharedConsole) -> Self {
+        let mut data = [0; MEMORY_MAX];
+        data[MemoryMappedRegister::MCR as usize] = MCR_CLOCK_ENABLE;
         Memory {
-            data: [0; MEMORY_MAX],
+            data,
+            keyboard: Keyboard::new(console),
+            devices: Vec::new(),
+            recording: None,
+            hooks: SharedHooks::default(),
+            watches: SharedWatches::default(),
         }
     }
 
-    pub fn read(&mut self, index: u16) -> u16 {
-        if index == MemoryMappedRegister::KBSR as u16 {
-            if check_key() {
-                self.data[MemoryMappedRegister::KBSR as usize] = 1 << 15;
-                self.data[MemoryMappedRegister::KBDR as usize] = get_char_byte().unwrap() as u16;
-            } else {
-                self.data[MemoryMappedRegister::KBSR as usize] = 0;
-            }
+    pub fn set_hooks(&mut self, hooks: SharedHooks) {
+        self.hooks = hooks;
+    }
+
+    pub fn set_watches(&mut self, watches: SharedWatches) {
+        self.watches = watches;
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Device>) -> Result<(), DeviceError> {
+        let range = device.range();
+        let overlaps = |other: &dyn Device| {
+            let other = other.range();
+            range.start() <= other.end() && other.start() <= range.end()
+        };
+        if overlaps(&self.keyboard) || self.devices.iter().any(|other| overlaps(other.as_ref())) {
+            return Err(DeviceError::Overlap { start: *range.start(), end: *range.end() });
         }
-        self.data[index as usize]
+        self.devices.push(device);
+        Ok(())
+    }
+
+    pub fn read(&mut self, index: u16) -> io::Result<u16> {
+        let value = match self.device_mut(index) {
+            Some(device) => device.read(index)?,
+            None => self.data[index as usize],
+        };
+        let value = self.hooks.borrow_mut().memory_read(index, value);
+        self.record(AccessKind::Read, index, value);
+        if self.watches.borrow().watches_address(index) {
+            self.watches.borrow_mut().memory_access(AccessKind::Read, index, value, value);
+        }
+        Ok(value)
+    }
+
+    /* reads a word without the side effects of memory-mapped registers */
+    pub fn peek(&self, index: u16) -> u16 {
+        match self.device(index) {
+            Some(device) => device.peek(index),
+            None => self.data[index as usize],
+        }
+    }
+
+    pub fn write(&mut self, index: u16, value: u16) -> io::Result<()> {
+        let value = self.hooks.borrow_mut().memory_write(index, value);
+        let watched = self.watches.borrow().watches_address(index);
+        let old = if watched { self.peek(index) } else { 0 };
+        match self.device_mut(index) {
+            Some(device) => device.write(index, value)?,
+            None => self.data[index as usize] = value,
+        }
+        self.record(AccessKind::Write, index, value);
+        if watched {
+            self.watches.borrow_mut().memory_access(AccessKind::Write, index, old, value);
+        }
+        Ok(())
+    }
+
+    /* keeps a log of every read and write (not peek or load) until take_recording */
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn take_recording(&mut self) -> Vec<MemoryAccess> {
+        self.recording.take().unwrap_or_default()
+    }
+
+    fn record(&mut self, kind: AccessKind, address: u16, value: u16) {
+        if let Some(recording) = &mut self.recording {
+            recording.push(MemoryAccess { kind, address, value });
+        }
+    }
+
+    /* copies straight into RAM, bypassing devices */
+    pub fn load(&mut self, origin: u16, words: &[u16]) -> Result<(), MemoryError> {
+        let start = origin as usize;
+        let target = self
+            .data
+            .get_mut(start..start + words.len())
+            .ok_or(MemoryError::OutOfBounds { origin, length: words.len() })?;
+        target.copy_from_slice(words);
+        Ok(())
     }
 
-    pub fn write(&mut self, index: u16, value: u16) {
+    /* stores one word straight into RAM, like load */
+    pub fn poke(&mut self, index: u16, value: u16) {
         self.data[index as usize] = value
     }
+
+    pub fn read_key(&mut self) -> io::Result<u8> {
+        self.keyboard.read_key()
+    }
+
+    /* interrupt requests from every device, in no particular order */
+    pub fn pending_interrupts(&mut self) -> io::Result<Vec<Interrupt>> {
+        let mut interrupts = Vec::new();
+        interrupts.extend(self.keyboard.interrupt()?);
+        for device in &mut self.devices {
+            interrupts.extend(device.interrupt()?);
+        }
+        Ok(interrupts)
+    }
+
+    pub fn tick(&mut self) {
+        self.keyboard.tick();
+        for device in &mut self.devices {
+            device.tick();
+        }
+    }
+
+    fn device(&self, index: u16) -> Option<&dyn Device> {
+        if self.keyboard.range().contains(&index) {
+            return Some(&self.keyboard);
+        }
+        self.devices
+            .iter()
+            .find(|device| device.range().contains(&index))
+            .map(|device| device.as_ref())
+    }
+
+    fn device_mut(&mut self, index: u16) -> Option<&mut dyn Device> {
+        if self.keyboard.range().contains(&index) {
+            return Some(&mut self.keyboard);
+        }
+        match self.devices.iter_mut().find(|device| device.range().contains(&index)) {
+            Some(device) => Some(device.as_mut()),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::RangeInclusive;
+
+    /* counts reads at its one address and raises an interrupt once a write arms it */
+    struct Counter {
+        reads: u16,
+        armed: bool,
+    }
+
+    impl Device for Counter {
+        fn range(&self) -> RangeInclusive<u16> {
+            0xFE10..=0xFE10
+        }
+
+        fn read(&mut self, _address: u16) -> io::Result<u16> {
+            self.reads += 1;
+            Ok(self.reads)
+        }
+
+        fn peek(&self, _address: u16) -> u16 {
+            self.reads
+        }
+
+        fn write(&mut self, _address: u16, value: u16) -> io::Result<()> {
+            self.armed = value != 0;
+            Ok(())
+        }
+
+        fn interrupt(&mut self) -> io::Result<Option<Interrupt>> {
+            Ok(self.armed.then_some(Interrupt { vector: 0x81, priority: 6 }))
+        }
+    }
+
+    #[test]
+    fn test_device_bus() {
+        let mut memory = Memory::new();
+        memory.attach(Box::new(Counter { reads: 0, armed: false })).unwrap();
+        assert_eq!(
+            memory.attach(Box::new(Counter { reads: 0, armed: false })),
+            Err(DeviceError::Overlap { start: 0xFE10, end: 0xFE10 })
+        );
+        assert_eq!(memory.read(0xFE10).unwrap(), 1);
+        assert_eq!(memory.read(0xFE10).unwrap(), 2);
+        assert_eq!(memory.peek(0xFE10), 2);
+        assert_eq!(memory.pending_interrupts().unwrap(), vec![]);
+        memory.write(0xFE10, 1).unwrap();
+        assert_eq!(memory.pending_interrupts().unwrap(), vec![Interrupt { vector: 0x81, priority: 6 }]);
+        memory.write(0xFE11, 7).unwrap();
+        assert_eq!(memory.peek(0xFE11), 7);
+    }
+
+    #[test]
+    fn test_load_bounds() {
+        let mut memory = Memory::new();
+        memory.load(0xFFFE, &[1, 2]).unwrap();
+        assert_eq!(memory.peek(0xFFFF), 2);
+        assert_eq!(memory.load(0xFFFE, &[1, 2, 3]), Err(MemoryError::OutOfBounds { origin: 0xFFFE, length: 3 }));
+    }
 }
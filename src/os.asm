@@ -0,0 +1,220 @@
+; LC-3 operating system: vector tables, trap service routines, default exception and keyboard
+; handlers, and the boot code that starts the user program. TRAP pushes PSR and PC on the
+; supervisor stack and leaves R7 alone, so every service routine returns with RTI and saves R7
+; only around its own JSRs. Output polls DSR. Input reads KBDR directly, which waits for a key when
+; none is latched, so a program whose input has run out stops there instead of spinning on KBSR.
+; There is no display interrupt handler: DSR has no interrupt enable bit, so the display never
+; requests an interrupt, and WRITE_CHAR waits for it by polling.
+
+        .ORIG x0000
+
+; trap vector table, x0000-x00FF
+        .BLKW x20
+        .FILL TRAP_GETC         ; x20
+        .FILL TRAP_OUT          ; x21
+        .FILL TRAP_PUTS         ; x22
+        .FILL TRAP_IN           ; x23
+        .FILL TRAP_PUTSP        ; x24
+        .FILL TRAP_HALT         ; x25
+        .BLKW xDA
+
+; exception and interrupt vector table, x0100-x01FF
+        .FILL EXC_PRIVILEGE     ; x00
+        .FILL EXC_ILLEGAL       ; x01
+        .FILL EXC_ACCESS        ; x02
+        .BLKW x7D
+        .FILL INT_KEYBOARD      ; x80
+        .BLKW x7F
+
+; boot, at x0200: enter the user program through RTI with USER_PSR, USER_PC and USER_R0
+BOOT    LD R6, OS_SP
+        LD R0, USER_PSR
+        ADD R6, R6, #-1
+        STR R0, R6, #0
+        LD R0, USER_PC
+        ADD R6, R6, #-1
+        STR R0, R6, #0
+        LD R0, USER_R0
+        RTI
+
+; writes R0 to the display once DSR is ready
+WRITE_CHAR
+        ST R1, WRITE_R1
+WRITE_WAIT
+        LDI R1, OS_DSR
+        BRzp WRITE_WAIT
+        STI R0, OS_DDR
+        LD R1, WRITE_R1
+        RET
+
+TRAP_GETC
+        LDI R0, OS_KBDR
+        RTI
+
+TRAP_OUT
+        ST R7, OUT_R7
+        JSR WRITE_CHAR
+        LD R7, OUT_R7
+        RTI
+
+TRAP_PUTS
+        ST R0, PUTS_R0
+        ST R1, PUTS_R1
+        ST R7, PUTS_R7
+        ADD R1, R0, #0
+PUTS_LOOP
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BR PUTS_LOOP
+PUTS_DONE
+        LD R0, PUTS_R0
+        LD R1, PUTS_R1
+        LD R7, PUTS_R7
+        RTI
+
+TRAP_IN
+        ST R1, IN_R1
+        ST R7, IN_R7
+        LEA R1, IN_PROMPT
+IN_PROMPT_LOOP
+        LDR R0, R1, #0
+        BRz IN_WAIT
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BR IN_PROMPT_LOOP
+IN_WAIT LDI R0, OS_KBDR
+        JSR WRITE_CHAR
+        LD R1, IN_R1
+        LD R7, IN_R7
+        RTI
+
+; two characters per word, low byte first; the high byte is shifted down one bit at a time
+TRAP_PUTSP
+        ST R0, PUTSP_R0
+        ST R1, PUTSP_R1
+        ST R2, PUTSP_R2
+        ST R3, PUTSP_R3
+        ST R4, PUTSP_R4
+        ST R7, PUTSP_R7
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R2, R1, #0
+        BRz PUTSP_DONE
+        LD R0, LOW_BYTE
+        AND R0, R2, R0
+        JSR WRITE_CHAR
+        AND R3, R3, #0
+        AND R4, R4, #0
+        ADD R4, R4, #8
+PUTSP_SHIFT
+        ADD R3, R3, R3
+        ADD R2, R2, #0
+        BRzp PUTSP_ZERO
+        ADD R3, R3, #1
+PUTSP_ZERO
+        ADD R2, R2, R2
+        ADD R4, R4, #-1
+        BRp PUTSP_SHIFT
+        ADD R0, R3, #0
+        BRz PUTSP_NEXT
+        JSR WRITE_CHAR
+PUTSP_NEXT
+        ADD R1, R1, #1
+        BR PUTSP_LOOP
+PUTSP_DONE
+        LD R0, PUTSP_R0
+        LD R1, PUTSP_R1
+        LD R2, PUTSP_R2
+        LD R3, PUTSP_R3
+        LD R4, PUTSP_R4
+        LD R7, PUTSP_R7
+        RTI
+
+; prints HALT and clears the MCR clock-enable bit. The program's registers are put back first, so
+; the STI goes through R6, which in supervisor mode is the OS's stack pointer rather than the
+; program's. Setting the bit again resumes at HALT_RESUME, which restores R6 and returns.
+TRAP_HALT
+        ST R0, HALT_R0
+        ST R1, HALT_R1
+        ST R7, HALT_R7
+        LEA R1, HALT_MESSAGE
+HALT_LOOP
+        LDR R0, R1, #0
+        BRz HALT_STOP
+        JSR WRITE_CHAR
+        ADD R1, R1, #1
+        BR HALT_LOOP
+HALT_STOP
+        LDI R0, OS_MCR
+        LD R1, CLOCK_OFF
+        AND R0, R0, R1
+        ST R0, HALT_MCR
+        ST R6, HALT_R6
+        LD R0, HALT_R0
+        LD R1, HALT_R1
+        LD R7, HALT_R7
+        LD R6, HALT_MCR
+        STI R6, OS_MCR
+HALT_RESUME
+        LD R6, HALT_R6
+        RTI
+
+EXC_PRIVILEGE
+        LEA R0, PRIVILEGE_MESSAGE
+        BR EXC_REPORT
+EXC_ILLEGAL
+        LEA R0, ILLEGAL_MESSAGE
+        BR EXC_REPORT
+EXC_ACCESS
+        LEA R0, ACCESS_MESSAGE
+EXC_REPORT
+        PUTS
+        HALT
+
+; acknowledges the key so an enabled keyboard interrupt without a user handler doesn't repeat
+INT_KEYBOARD
+        ST R0, KEYBOARD_R0
+        LDI R0, OS_KBDR
+        LD R0, KEYBOARD_R0
+        RTI
+
+OS_KBDR .FILL xFE02
+OS_DSR  .FILL xFE04
+OS_DDR  .FILL xFE06
+OS_MCR  .FILL xFFFE
+OS_SP   .FILL x3000
+USER_PSR .FILL x8002   ; user mode, priority 0, Z
+USER_PC .FILL x3000
+USER_R0 .FILL 0
+LOW_BYTE .FILL x00FF
+CLOCK_OFF .FILL x7FFF
+
+WRITE_R1 .BLKW 1
+OUT_R7  .BLKW 1
+PUTS_R0 .BLKW 1
+PUTS_R1 .BLKW 1
+PUTS_R7 .BLKW 1
+IN_R1   .BLKW 1
+IN_R7   .BLKW 1
+PUTSP_R0 .BLKW 1
+PUTSP_R1 .BLKW 1
+PUTSP_R2 .BLKW 1
+PUTSP_R3 .BLKW 1
+PUTSP_R4 .BLKW 1
+PUTSP_R7 .BLKW 1
+HALT_R0 .BLKW 1
+HALT_R1 .BLKW 1
+HALT_R7 .BLKW 1
+HALT_R6 .BLKW 1
+HALT_MCR .BLKW 1
+KEYBOARD_R0 .BLKW 1
+
+IN_PROMPT .STRINGZ "Enter a character: "
+HALT_MESSAGE .STRINGZ "HALT"
+PRIVILEGE_MESSAGE .STRINGZ "\nprivilege mode violation\n"
+ILLEGAL_MESSAGE .STRINGZ "\nillegal opcode\n"
+ACCESS_MESSAGE .STRINGZ "\naccess control violation\n"
+
+        .END
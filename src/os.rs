@@ -0,0 +1,158 @@
+use crate::assembler::{assemble, Program};
+use crate::cpu::CPU;
+use crate::register::Register;
+use crate::trap::TrapMode;
+
+const OS_SOURCE: &str = include_str!("os.asm");
+
+/* where the OS boot code starts; it enters the user program at x3000 unless set_user_entry moved it */
+pub const OS_BOOT: u16 = 0x0200;
+
+pub fn assemble_os() -> Program {
+    assemble(OS_SOURCE).expect("the bundled OS source assembles")
+}
+
+/* loads the OS and switches TRAP to go through the vector table; load user images afterwards */
+pub fn install(cpu: &mut CPU) {
+    let program = assemble_os();
+    cpu.memory_mut()
+        .load(program.image.origin, &program.image.words)
+        .expect("the bundled OS fits in memory");
+    cpu.set_trap_mode(TrapMode::Vectored);
+}
+
+pub fn boot(cpu: &mut CPU) {
+    cpu.reset();
+    cpu.registers_mut().write(Register::PC, OS_BOOT);
+}
+
+/* patches the OS's USER_PC word so boot enters the user program at `entry` instead of x3000 */
+pub fn set_user_entry(cpu: &mut CPU, entry: u16) {
+    let user_pc = assemble_os().symbols["USER_PC"];
+    cpu.memory_mut().poke(user_pc, entry);
+}
+
+/*
+ * sets a register to the value the user program starts with. Boot sets R0, R6, COND and PC on its way
+ * there, so those go into the words it reads them from, or the saved user stack pointer, instead.
+ */
+pub fn set_user_register(cpu: &mut CPU, register: Register, value: u16) {
+    let symbols = assemble_os().symbols;
+    match register {
+        Register::R0 => cpu.memory_mut().poke(symbols["USER_R0"], value),
+        Register::R6 => cpu.registers_mut().set_user_stack_pointer(value),
+        Register::PC => set_user_entry(cpu, value),
+        Register::COND => {
+            let psr = cpu.memory().peek(symbols["USER_PSR"]);
+            cpu.memory_mut().poke(symbols["USER_PSR"], (psr & !0x7) | (value & 0x7));
+        },
+        _ => cpu.registers_mut().write(register, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::console::BufferConsole;
+    use crate::cpu::StepOutcome;
+
+    /* loads each source over the OS, so a program can replace vector table entries */
+    fn run_with_os(sources: &[&str]) -> (CPU, String) {
+        let console = Rc::new(RefCell::new(BufferConsole::new(b"")));
+        let mut cpu = CPU::with_console(console.clone(), 3);
+        install(&mut cpu);
+        for source in sources {
+            let program = assemble(source).unwrap();
+            cpu.memory_mut().load(program.image.origin, &program.image.words).unwrap();
+        }
+        boot(&mut cpu);
+        assert_eq!(cpu.run_for(100_000).unwrap(), StepOutcome::Halted);
+        let output = String::from_utf8(console.borrow().output().to_vec()).unwrap();
+        (cpu, output)
+    }
+
+    #[test]
+    fn test_os_layout() {
+        let program = assemble_os();
+        assert_eq!(program.symbols["BOOT"], OS_BOOT);
+        assert_eq!(program.image.words[0x25], program.symbols["TRAP_HALT"]);
+        assert_eq!(program.image.words[0x180], program.symbols["INT_KEYBOARD"]);
+    }
+
+    #[test]
+    fn test_os_trap_service_routines() {
+        let (cpu, output) = run_with_os(&[
+            ".ORIG x3000
+                 LEA R0, HELLO
+                 PUTS
+                 LEA R0, PACKED
+                 PUTSP
+                 LD R0, BANG
+                 OUT
+                 ADD R3, R6, #0
+                 ADD R4, R0, #0
+                 ADD R5, R7, #0
+                 HALT
+            HELLO  .STRINGZ \"hi \"
+            PACKED .FILL x6F79
+                   .FILL x0075
+                   .FILL 0
+            BANG   .FILL x21
+            .END",
+        ]);
+        assert_eq!(output, "hi you!HALT");
+        /* the program runs in user mode on its own stack, which nothing has set up */
+        assert_eq!(cpu.registers().read(Register::R3), 0);
+        assert_eq!(cpu.registers().read(Register::R4), 0x21);
+        /* vectored TRAPs return through RTI and leave R7 alone */
+        assert_eq!(cpu.registers().read(Register::R5), 0);
+    }
+
+    #[test]
+    fn test_halt_resumes_when_the_clock_restarts() {
+        let (mut cpu, _) = run_with_os(&[
+            ".ORIG x3000
+                 LD R6, STACK
+                 AND R0, R0, #0
+                 HALT
+                 ADD R1, R6, #0
+                 ADD R2, R0, #0
+                 HALT
+            STACK .FILL x5000
+            .END",
+        ]);
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::R1), 0);
+        cpu.memory_mut().write(0xFFFE, 0x8000).unwrap();
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+        assert_eq!(cpu.registers().read(Register::R1), 0x5000);
+        assert_eq!(cpu.registers().read(Register::R2), 0);
+    }
+
+    #[test]
+    fn test_user_trap_handler_and_exceptions() {
+        let (_, output) = run_with_os(&[
+            ".ORIG x3000
+                 TRAP x26
+                 RES
+            HANDLER LD R0, STAR
+                 OUT
+                 RTI
+            STAR   .FILL x2A
+            .END",
+            ".ORIG x0026
+                 .FILL x3002
+            .END",
+        ]);
+        assert_eq!(output, "*\nillegal opcode\nHALT");
+    }
+
+    #[test]
+    fn test_user_program_cannot_touch_system_space() {
+        let (cpu, output) = run_with_os(&[".ORIG x3000\nAND R0, R0, #0\nSTR R0, R0, #0\nHALT\n.END"]);
+        assert_eq!(output, "\naccess control violation\nHALT");
+        assert_eq!(cpu.memory().peek(0x0000), 0);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::flag::ConditionFlag;
+use crate::hook::SharedHooks;
+use crate::watch::SharedWatches;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Register {
@@ -15,6 +17,31 @@ pub enum Register {
     COUNT
 }
 
+pub const GENERAL_REGISTERS: [Register; 8] = [
+    Register::R0,
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+    Register::R6,
+    Register::R7,
+];
+
+/* the registers the debugger shows and GDB numbers, in that order */
+pub const VISIBLE_REGISTERS: [Register; 10] = [
+    Register::R0,
+    Register::R1,
+    Register::R2,
+    Register::R3,
+    Register::R4,
+    Register::R5,
+    Register::R6,
+    Register::R7,
+    Register::PC,
+    Register::COND,
+];
+
 #[derive(Debug, PartialEq)]
 pub enum RegisterError {
     UnknownRegister(u16),
@@ -40,14 +67,45 @@ impl Register {
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Privilege {
+    Supervisor,
+    User,
+}
+
+/* initial supervisor stack pointer, growing down from just below user space */
+const SUPERVISOR_STACK: u16 = 0x3000;
+
+/*
+ * R6 is always the active stack pointer; the inactive mode's stack pointer is kept in saved_usp or
+ * saved_ssp. The PSR is assembled from the privilege bit (15), the priority level (10-8) and COND (2-0).
+ */
 pub struct Registers {
     data: [u16; 10],
+    privilege: Privilege,
+    priority: u16,
+    saved_usp: u16,
+    saved_ssp: u16,
+    hooks: SharedHooks,
+    watches: SharedWatches,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
 }
 
 impl Registers {
     pub fn new() -> Self {
         Registers {
             data: [0; 10],
+            privilege: Privilege::Supervisor,
+            priority: 0,
+            saved_usp: 0,
+            saved_ssp: SUPERVISOR_STACK,
+            hooks: SharedHooks::default(),
+            watches: SharedWatches::default(),
         }
     }
 
@@ -55,17 +113,76 @@ impl Registers {
         self.data[register as usize]
     }
 
+    /* every change to a register, including COND and the R6 stack swaps, goes through here */
     pub fn write(&mut self, register: Register, value: u16) {
-        self.data[register as usize] = value
+        let value = self.hooks.borrow_mut().register_write(register, value);
+        let old = self.data[register as usize];
+        self.data[register as usize] = value;
+        self.watches.borrow_mut().register_write(register, old, value)
+    }
+
+    pub fn set_hooks(&mut self, hooks: SharedHooks) {
+        self.hooks = hooks;
+    }
+
+    pub fn set_watches(&mut self, watches: SharedWatches) {
+        self.watches = watches;
     }
 
     pub fn update_flags(&mut self, register: Register) {
         let value = self.data[register as usize];
-        self.data[Register::COND as usize] = match value {
+        let cond = match value {
             0 => ConditionFlag::ZRO as u16,
             v if v >> 15 == 1 => ConditionFlag::NEG as u16,
             _ => ConditionFlag::POS as u16,
         };
+        self.write(Register::COND, cond);
+    }
+
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    pub fn priority(&self) -> u16 {
+        self.priority
+    }
+
+    pub fn psr(&self) -> u16 {
+        let privilege = match self.privilege {
+            Privilege::Supervisor => 0,
+            Privilege::User => 1 << 15,
+        };
+        privilege | (self.priority << 8) | (self.data[Register::COND as usize] & 0x7)
+    }
+
+    /* sets priority and COND from a PSR; the privilege bit goes through enter_user/enter_supervisor */
+    pub fn set_psr(&mut self, psr: u16) {
+        self.priority = (psr >> 8) & 0x7;
+        self.write(Register::COND, psr & 0x7);
+    }
+
+    /* R6 in user mode; in supervisor mode, the value R6 takes when the machine next enters user mode */
+    pub fn set_user_stack_pointer(&mut self, value: u16) {
+        match self.privilege {
+            Privilege::User => self.write(Register::R6, value),
+            Privilege::Supervisor => self.saved_usp = value,
+        }
+    }
+
+    pub fn enter_supervisor(&mut self) {
+        if self.privilege == Privilege::User {
+            self.saved_usp = self.data[Register::R6 as usize];
+            self.write(Register::R6, self.saved_ssp);
+            self.privilege = Privilege::Supervisor;
+        }
+    }
+
+    pub fn enter_user(&mut self) {
+        if self.privilege == Privilege::Supervisor {
+            self.saved_ssp = self.data[Register::R6 as usize];
+            self.write(Register::R6, self.saved_usp);
+            self.privilege = Privilege::User;
+        }
     }
 }
 
@@ -92,4 +209,21 @@ mod tests {
     fn test_register_from_u16_invalid() {
         assert_eq!(Register::from_u16(16), Err(RegisterError::UnknownRegister(16)));
     }
+
+    #[test]
+    fn test_psr_and_stack_switching() {
+        let mut registers = Registers::new();
+        registers.set_psr(0x8302);
+        assert_eq!(registers.psr(), 0x0302);
+        registers.write(Register::R6, 0x2FF0);
+        registers.enter_user();
+        assert_eq!(registers.psr(), 0x8302);
+        assert_eq!(registers.read(Register::R6), 0);
+        registers.write(Register::R6, 0xFDFF);
+        registers.enter_supervisor();
+        assert_eq!(registers.privilege(), Privilege::Supervisor);
+        assert_eq!(registers.read(Register::R6), 0x2FF0);
+        registers.enter_user();
+        assert_eq!(registers.read(Register::R6), 0xFDFF);
+    }
 }
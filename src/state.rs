@@ -0,0 +1,105 @@
+use std::error;
+use std::fmt;
+use crate::assembler::parse_number;
+use crate::register::Register;
+use crate::symbols::SymbolTable;
+
+/* one value to put in place before the program starts */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Preset {
+    Register(Register, u16),
+    Memory(u16, u16),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    Malformed(String),
+    UnknownTarget(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Malformed(text) => write!(f, "expected `target = value`, found `{}`", text),
+            StateError::UnknownTarget(text) => {
+                write!(f, "`{}` is not a register, an address or a known label", text)
+            },
+            StateError::InvalidValue(text) => write!(f, "`{}` is not a 16-bit value or a known label", text),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+/*
+ * Parses `target = value` assignments separated by commas or newlines; `;` starts a comment. A target
+ * is R0-R7, PC, COND, an address or a label; a value is a number in assembler syntax or a label.
+ */
+pub fn parse_state(text: &str, symbols: &SymbolTable) -> Result<Vec<Preset>, StateError> {
+    let mut presets = Vec::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or("");
+        for assignment in line.split(',').map(str::trim).filter(|assignment| !assignment.is_empty()) {
+            let Some((target, value)) = assignment.split_once('=') else {
+                return Err(StateError::Malformed(assignment.to_string()));
+            };
+            let (target, value) = (target.trim(), value.trim());
+            let value = parse_value(value, symbols).ok_or_else(|| StateError::InvalidValue(value.to_string()))?;
+            let preset = match parse_register(target) {
+                Some(register) => Preset::Register(register, value),
+                None => {
+                    let address = parse_value(target, symbols)
+                        .ok_or_else(|| StateError::UnknownTarget(target.to_string()))?;
+                    Preset::Memory(address, value)
+                },
+            };
+            presets.push(preset);
+        }
+    }
+    Ok(presets)
+}
+
+/* a number in assembler syntax (negative values wrap to 16 bits) or a label */
+pub fn parse_value(text: &str, symbols: &SymbolTable) -> Option<u16> {
+    match parse_number(text) {
+        Some(value) if (-0x8000..=0xFFFF).contains(&value) => Some(value as u16),
+        Some(_) => None,
+        None => symbols.get(text).copied(),
+    }
+}
+
+fn parse_register(text: &str) -> Option<Register> {
+    match text.to_ascii_uppercase().as_str() {
+        "PC" => Some(Register::PC),
+        "COND" => Some(Register::COND),
+        name => {
+            let index = name.strip_prefix('R')?.parse::<u16>().ok().filter(|index| *index < 8)?;
+            Register::from_u16(index).ok()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_state() {
+        let symbols = SymbolTable::from([("DATA".to_string(), 0x4000)]);
+        let text = "r1 = 5, R2=x10 ; comment\nDATA = #-1\nx4001 = DATA\nPC = x3005\n";
+        assert_eq!(
+            parse_state(text, &symbols),
+            Ok(vec![
+                Preset::Register(Register::R1, 5),
+                Preset::Register(Register::R2, 0x10),
+                Preset::Memory(0x4000, 0xFFFF),
+                Preset::Memory(0x4001, 0x4000),
+                Preset::Register(Register::PC, 0x3005),
+            ])
+        );
+        assert_eq!(parse_state("R8 = 1", &symbols), Err(StateError::UnknownTarget("R8".to_string())));
+        assert_eq!(parse_state("R1 = LOOP", &symbols), Err(StateError::InvalidValue("LOOP".to_string())));
+        assert_eq!(parse_state("R1 5", &symbols), Err(StateError::Malformed("R1 5".to_string())));
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+pub type SymbolTable = BTreeMap<String, u16>;
+
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+    InvalidLine(usize),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::InvalidLine(line) => write!(f, "invalid symbol table entry on line {}", line),
+        }
+    }
+}
+
+impl error::Error for SymbolError {}
+
+pub fn name_for(symbols: &SymbolTable, address: u16) -> Option<&str> {
+    symbols
+        .iter()
+        .find(|(_, &symbol_address)| symbol_address == address)
+        .map(|(name, _)| name.as_str())
+}
+
+/* same layout as the .sym files written by the classic lc3as */
+pub fn to_sym_file(symbols: &SymbolTable) -> String {
+    let mut text = String::from(
+        "// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n",
+    );
+    let mut entries: Vec<(&String, &u16)> = symbols.iter().collect();
+    entries.sort_by_key(|(name, address)| (**address, name.as_str()));
+    for (name, address) in entries {
+        text.push_str(&format!("//\t{:<16}  {:04X}\n", name, address));
+    }
+    text
+}
+
+pub fn parse_sym_file(text: &str) -> Result<SymbolTable, SymbolError> {
+    const HEADERS: [&str; 4] = ["Symbol table", "Scope level", "Symbol Name", "---"];
+    let mut symbols = SymbolTable::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim_start_matches('/').trim();
+        if line.is_empty() || HEADERS.iter().any(|header| line.starts_with(header)) {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, address] = fields.as_slice() else {
+            return Err(SymbolError::InvalidLine(index + 1));
+        };
+        let address = u16::from_str_radix(address.trim_start_matches(['x', 'X']), 16)
+            .map_err(|_| SymbolError::InvalidLine(index + 1))?;
+        symbols.insert(name.to_string(), address);
+    }
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sym_file_round_trip() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP".to_string(), 0x3002);
+        symbols.insert("DATA".to_string(), 0x3010);
+        let text = to_sym_file(&symbols);
+        assert!(text.contains("//\tLOOP              3002\n"));
+        assert_eq!(parse_sym_file(&text), Ok(symbols));
+    }
+
+    #[test]
+    fn test_parse_sym_file_invalid() {
+        assert_eq!(parse_sym_file("//\tLOOP  30G2\n"), Err(SymbolError::InvalidLine(1)));
+    }
+}
@@ -0,0 +1,95 @@
+use std::io;
+use std::io::IsTerminal;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::panic;
+use std::sync::OnceLock;
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, SpecialCharacterIndices};
+
+/* stdin's settings before raw mode, for the SIGINT handler and the panic hook */
+static ORIGINAL: OnceLock<libc::termios> = OnceLock::new();
+
+/*
+ * Puts a terminal in non-canonical, no-echo mode so keys reach the program one at a time, and puts
+ * the original settings back when dropped. ISIG stays on so Ctrl-C still raises SIGINT.
+ */
+pub struct RawMode {
+    fd: RawFd,
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enter(fd: RawFd) -> io::Result<RawMode> {
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let mut termios = tcgetattr(borrowed)?;
+        let original: libc::termios = termios.clone().into();
+        termios.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(borrowed, SetArg::TCSANOW, &termios)?;
+        Ok(RawMode { fd, original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore(self.fd, &self.original);
+    }
+}
+
+fn restore(fd: RawFd, original: &libc::termios) {
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, original);
+    }
+}
+
+/* only tcsetattr, write and _exit here, since they are async-signal-safe */
+extern "C" fn on_sigint(_: libc::c_int) {
+    if let Some(original) = ORIGINAL.get() {
+        restore(libc::STDIN_FILENO, original);
+    }
+    unsafe {
+        libc::write(libc::STDOUT_FILENO, b"\n".as_ptr().cast(), 1);
+        libc::_exit(130);
+    }
+}
+
+/* raw mode on stdin until the guard drops, or None when stdin isn't a terminal */
+pub fn enable_raw_mode() -> io::Result<Option<RawMode>> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(None);
+    }
+    let raw_mode = RawMode::enter(stdin.as_raw_fd())?;
+    if ORIGINAL.set(raw_mode.original).is_ok() {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(original) = ORIGINAL.get() {
+                restore(libc::STDIN_FILENO, original);
+            }
+            previous(info)
+        }));
+        let action = SigAction::new(SigHandler::Handler(on_sigint), SaFlags::empty(), SigSet::empty());
+        unsafe { sigaction(Signal::SIGINT, &action) }?;
+    }
+    Ok(Some(raw_mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::pty::openpty;
+
+    #[test]
+    fn test_raw_mode_restores_on_drop() {
+        let pty = openpty(None, None).unwrap();
+        let flags = || tcgetattr(&pty.slave).unwrap().local_flags;
+        assert!(flags().contains(LocalFlags::ICANON | LocalFlags::ECHO));
+        let raw_mode = RawMode::enter(pty.slave.as_raw_fd()).unwrap();
+        assert!(!flags().intersects(LocalFlags::ICANON | LocalFlags::ECHO));
+        assert!(flags().contains(LocalFlags::ISIG));
+        drop(raw_mode);
+        assert!(flags().contains(LocalFlags::ICANON | LocalFlags::ECHO));
+    }
+}
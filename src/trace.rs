@@ -0,0 +1,200 @@
+use std::io;
+use std::io::Write;
+use crate::disassembler::disassemble;
+use crate::flag::ConditionFlag;
+use crate::register::{Register, GENERAL_REGISTERS};
+use crate::symbols::SymbolTable;
+use crate::utils::json_string;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/* one word that went over the bus, with the value read or written */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: u16,
+    pub value: u16,
+}
+
+/* what one instruction did; `changes` holds (register, old, new) for each general register it changed */
+#[derive(Debug, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub instruction: u16,
+    pub changes: Vec<(Register, u16, u16)>,
+    pub cond: u16,
+    pub accesses: Vec<MemoryAccess>,
+}
+
+impl TraceEntry {
+    /* the general registers that differ between the two snapshots */
+    pub fn diff(before: &[u16; 8], after: &[u16; 8]) -> Vec<(Register, u16, u16)> {
+        GENERAL_REGISTERS
+            .iter()
+            .zip(before.iter().zip(after))
+            .filter(|(_, (old, new))| old != new)
+            .map(|(register, (old, new))| (*register, *old, *new))
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+/* writes one line per executed instruction; labels from `symbols` are used in the disassembly */
+pub struct Tracer {
+    writer: Box<dyn Write>,
+    format: TraceFormat,
+    symbols: Option<SymbolTable>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write>, format: TraceFormat, symbols: Option<SymbolTable>) -> Self {
+        Tracer { writer, format, symbols }
+    }
+
+    pub fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        let disassembly = disassemble(entry.pc, entry.instruction, self.symbols.as_ref());
+        let line = match self.format {
+            TraceFormat::Text => text_line(entry, &disassembly),
+            TraceFormat::JsonLines => json_line(entry, &disassembly),
+        };
+        writeln!(self.writer, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn cond_name(cond: u16) -> String {
+    match cond {
+        c if c == ConditionFlag::NEG as u16 => "N".to_string(),
+        c if c == ConditionFlag::ZRO as u16 => "Z".to_string(),
+        c if c == ConditionFlag::POS as u16 => "P".to_string(),
+        c => format!("x{:X}", c),
+    }
+}
+
+/* x3002  x2205  LD R1, DATA                R1 x0000->x0005  COND P  read x3008=x0005 */
+fn text_line(entry: &TraceEntry, disassembly: &str) -> String {
+    let mut line = format!("x{:04X}  x{:04X}  {:<24}", entry.pc, entry.instruction, disassembly);
+    for (register, old, new) in &entry.changes {
+        line.push_str(&format!("  {:?} x{:04X}->x{:04X}", register, old, new));
+    }
+    line.push_str(&format!("  COND {}", cond_name(entry.cond)));
+    for access in &entry.accesses {
+        let kind = match access.kind {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+        };
+        line.push_str(&format!("  {} x{:04X}=x{:04X}", kind, access.address, access.value));
+    }
+    line
+}
+
+fn json_line(entry: &TraceEntry, disassembly: &str) -> String {
+    let changes: Vec<String> = entry
+        .changes
+        .iter()
+        .map(|(register, old, new)| format!("\"{:?}\":{{\"old\":{},\"new\":{}}}", register, old, new))
+        .collect();
+    let accesses = |kind: AccessKind| {
+        let accesses: Vec<String> = entry
+            .accesses
+            .iter()
+            .filter(|access| access.kind == kind)
+            .map(|access| format!("{{\"address\":{},\"value\":{}}}", access.address, access.value))
+            .collect();
+        accesses.join(",")
+    };
+    format!(
+        "{{\"pc\":{},\"word\":{},\"disasm\":{},\"registers\":{{{}}},\"cond\":\"{}\",\"reads\":[{}],\"writes\":[{}]}}",
+        entry.pc,
+        entry.instruction,
+        json_string(disassembly),
+        changes.join(","),
+        cond_name(entry.cond),
+        accesses(AccessKind::Read),
+        accesses(AccessKind::Write)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::machine::tests::test_machine;
+
+    /* a writer the test can still read after handing it to a tracer */
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /* accepts every line but can't get them to disk */
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("no space left on device"))
+        }
+    }
+
+    #[test]
+    fn test_trace_write_errors_end_the_run() {
+        let (mut cpu, _, _) = test_machine(".ORIG x3000\nHALT\n.END", b"", |machine| {
+            machine.tracer(Tracer::new(Box::new(Full), TraceFormat::Text, None))
+        });
+        let error = cpu.run().unwrap_err();
+        assert_eq!(error.to_string(), "fault at x3001 (instruction x0000): I/O error: no space left on device");
+    }
+
+    #[test]
+    fn test_trace() {
+        let source = ".ORIG x3000\nLD R1, DATA\nST R1, COPY\nHALT\nDATA .FILL x0005\nCOPY .BLKW 1\n.END";
+        let (text, json) = (Shared::default(), Shared::default());
+        let (mut cpu, _, symbols) = test_machine(source, b"", |machine| {
+            machine.tracer(Tracer::new(Box::new(json.clone()), TraceFormat::JsonLines, None))
+        });
+        /* the symbols only exist once the program is assembled */
+        cpu.add_tracer(Tracer::new(Box::new(text.clone()), TraceFormat::Text, Some(symbols)));
+        cpu.run().unwrap();
+        let text = String::from_utf8(text.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "x3000  x2202  LD R1, DATA               R1 x0000->x0005  COND P  read x3003=x0005"
+        );
+        assert_eq!(lines[1], "x3001  x3202  ST R1, COPY               COND P  write x3004=x0005");
+        assert!(lines[2].starts_with("x3002  xF025  HALT                      R7 x0000->x3003  COND P"));
+        let json = String::from_utf8(json.0.borrow().clone()).unwrap();
+        assert_eq!(
+            json.lines().next().unwrap(),
+            "{\"pc\":12288,\"word\":8706,\"disasm\":\"LD R1, x3003\",\"registers\":{\"R1\":{\"old\":0,\"new\":5}},\
+             \"cond\":\"P\",\"reads\":[{\"address\":12291,\"value\":5}],\"writes\":[]}"
+        );
+    }
+}
@@ -8,6 +8,17 @@ pub enum TrapCode {
     HALT = 0x25   /* halt the program */
 }
 
+/*
+ * Native runs the service routines in Rust and leaves the return address in R7. Vectored follows the
+ * third-edition ISA: TRAP pushes PSR and PC on the supervisor stack, enters supervisor mode and jumps
+ * through mem[trapvect8]; the routine returns with RTI and R7 is left alone.
+ */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TrapMode {
+    Native,
+    Vectored,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum TrapCodeError {
     UnknownTrapCode(u16),
@@ -0,0 +1,154 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use crate::register::Register;
+use crate::trace::AccessKind;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, /* read or write */
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Watchpoint {
+    Memory { kind: WatchKind, start: u16, end: u16 }, /* start..=end */
+    Register(Register),                               /* fires when a write changes the value */
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Watched {
+    Memory(AccessKind, u16),
+    Register(Register),
+}
+
+/* one access that matched a watchpoint; for reads old and new are both the value read */
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WatchHit {
+    pub watched: Watched,
+    pub old: u16,
+    pub new: u16,
+    pub pc: u16, /* address of the instruction that made the access */
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watchpoint::Memory { kind, start, end } if start == end => write!(f, "{:?} x{:04X}", kind, start),
+            Watchpoint::Memory { kind, start, end } => write!(f, "{:?} x{:04X}-x{:04X}", kind, start, end),
+            Watchpoint::Register(register) => write!(f, "change {:?}", register),
+        }
+    }
+}
+
+/*
+ * The watchpoints of one machine, shared by its memory and registers, which report every access to
+ * them. The CPU calls begin after fetching each instruction and collects the hits once it has run.
+ */
+#[derive(Default)]
+pub struct Watches {
+    watchpoints: Vec<Watchpoint>,
+    pc: u16,
+    hits: Vec<WatchHit>,
+}
+
+pub type SharedWatches = Rc<RefCell<Watches>>;
+
+impl Watches {
+    pub fn add(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        (index < self.watchpoints.len()).then(|| self.watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn begin(&mut self, pc: u16) {
+        self.pc = pc;
+        self.hits.clear();
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        mem::take(&mut self.hits)
+    }
+
+    /* whether an access to `address` could hit anything, so callers only fetch the old value when needed */
+    pub fn watches_address(&self, address: u16) -> bool {
+        self.watchpoints.iter().any(|watchpoint| match watchpoint {
+            Watchpoint::Memory { start, end, .. } => (*start..=*end).contains(&address),
+            Watchpoint::Register(_) => false,
+        })
+    }
+
+    pub fn memory_access(&mut self, access: AccessKind, address: u16, old: u16, new: u16) {
+        let hit = self.watchpoints.iter().any(|watchpoint| match *watchpoint {
+            Watchpoint::Memory { kind, start, end } => {
+                let kind_matches = match kind {
+                    WatchKind::Read => access == AccessKind::Read,
+                    WatchKind::Write => access == AccessKind::Write,
+                    WatchKind::Access => true,
+                };
+                kind_matches && (start..=end).contains(&address)
+            },
+            Watchpoint::Register(_) => false,
+        });
+        if hit {
+            self.hits.push(WatchHit { watched: Watched::Memory(access, address), old, new, pc: self.pc });
+        }
+    }
+
+    pub fn register_write(&mut self, register: Register, old: u16, new: u16) {
+        if old != new && self.watchpoints.contains(&Watchpoint::Register(register)) {
+            self.hits.push(WatchHit { watched: Watched::Register(register), old, new, pc: self.pc });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StepOutcome;
+    use crate::machine::tests::test_machine;
+
+    #[test]
+    fn test_watchpoints() {
+        let source = ".ORIG x3000
+                 LD R1, DATA
+                 ADD R2, R1, #0
+                 ST R1, COPY
+                 ST R1, COPY
+                 HALT
+             DATA .FILL x0005
+             COPY .BLKW 2
+             .END";
+        let (mut cpu, _, _) = test_machine(source, b"", |machine| machine);
+        cpu.add_watchpoint(Watchpoint::Memory { kind: WatchKind::Read, start: 0x3005, end: 0x3005 });
+        cpu.add_watchpoint(Watchpoint::Memory { kind: WatchKind::Write, start: 0x3006, end: 0x3007 });
+        cpu.add_watchpoint(Watchpoint::Register(Register::R2));
+
+        let read = WatchHit { watched: Watched::Memory(AccessKind::Read, 0x3005), old: 5, new: 5, pc: 0x3000 };
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Watched(vec![read]));
+        let change = WatchHit { watched: Watched::Register(Register::R2), old: 0, new: 5, pc: 0x3001 };
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Watched(vec![change]));
+        let write = WatchHit { watched: Watched::Memory(AccessKind::Write, 0x3006), old: 0, new: 5, pc: 0x3002 };
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Watched(vec![write]));
+        /* a write of the same value is still a write */
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Watched(vec![WatchHit { old: 5, pc: 0x3003, ..write }]));
+
+        let removed = cpu.remove_watchpoint(1);
+        assert_eq!(removed, Some(Watchpoint::Memory { kind: WatchKind::Write, start: 0x3006, end: 0x3007 }));
+        assert_eq!(cpu.watchpoints().len(), 2);
+
+        /* HALT clears the clock bit in MCR; the hit comes first, then the halt */
+        cpu.add_watchpoint(Watchpoint::Memory { kind: WatchKind::Write, start: 0xFFFE, end: 0xFFFE });
+        let clock = WatchHit { watched: Watched::Memory(AccessKind::Write, 0xFFFE), old: 0x8000, new: 0, pc: 0x3004 };
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Watched(vec![clock]));
+        assert_eq!(cpu.run().unwrap(), StepOutcome::Halted);
+    }
+}